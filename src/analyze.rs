@@ -0,0 +1,73 @@
+//! Loudness report of the audio files in a folder.
+use crate::{create_playlist_from_dir, loudness_cache_path};
+use sensit_audio_cli as lib;
+use std::{
+    io::{self, Write},
+    path::Path,
+};
+
+/// Analyzes the loudness of the audio files in a folder,
+/// printing a report and caching the results for playback.
+///
+/// # Arguments
+/// + `dir`: Path to directory containing sound files.
+/// + `force`: Analyze files even if they were already analyzed.
+pub fn run(dir: impl AsRef<Path>, force: bool) {
+    let playlist = create_playlist_from_dir(dir.as_ref());
+    if playlist.is_empty() {
+        tracing::info!("No audio files are present");
+        return;
+    }
+
+    let cache_path = loudness_cache_path();
+    let mut cache = match cache_path.as_ref().map(lib::LoudnessCache::load) {
+        Some(Ok(cache)) => cache,
+        Some(Err(err)) => {
+            tracing::warn!(?err);
+            lib::LoudnessCache::default()
+        }
+        None => lib::LoudnessCache::default(),
+    };
+
+    let mut stdout = io::stdout();
+    write_trace!(
+        stdout,
+        "{:>10} {:>8} {:>10}  {}\n",
+        "integrated",
+        "range",
+        "true peak",
+        "file"
+    );
+    for file in playlist.iter() {
+        match cache.analyze(file, force, lib::DecodeErrorPolicy::default()) {
+            Ok(loudness) => {
+                write_trace!(
+                    stdout,
+                    "{:>5.1} LUFS {:>5.1} LU {:>5.1} dBTP  {}\n",
+                    loudness.integrated,
+                    loudness.range,
+                    loudness.true_peak,
+                    file.to_string_lossy()
+                );
+            }
+            Err(err) => {
+                tracing::warn!(?err);
+                write_trace!(
+                    stdout,
+                    "{:>30}  {}\n",
+                    "could not analyze",
+                    file.to_string_lossy()
+                );
+            }
+        }
+    }
+
+    match cache_path {
+        Some(cache_path) => {
+            if let Err(err) = cache.save(&cache_path) {
+                tracing::error!(?err);
+            }
+        }
+        None => tracing::warn!("no cache location, results will not be used for playback"),
+    }
+}
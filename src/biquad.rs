@@ -0,0 +1,30 @@
+//! Second order IIR filters.
+
+/// Second order IIR filter, with coefficients normalized by `a0`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    /// # Arguments
+    /// + `b`: Feed forward coefficients.
+    /// + `a`: Feedback coefficients `a1` and `a2`.
+    pub fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self {
+            b,
+            a,
+            state: [0.0; 2],
+        }
+    }
+
+    pub fn process(&mut self, x: f64) -> f64 {
+        // transposed direct form II
+        let y = self.b[0] * x + self.state[0];
+        self.state[0] = self.b[1] * x - self.a[0] * y + self.state[1];
+        self.state[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
@@ -0,0 +1,380 @@
+//! Cache of decoded audio, so recently played tracks play again without decoding.
+use crate::{DecodeFormat, ReplayGainTags, SourceFormat};
+use std::{
+    cmp,
+    collections::VecDeque,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Audio of a track, decoded to the format of an [`AudioOutput`](crate::AudioOutput).
+/// Gain, filters, and the equalizer are applied as it is played.
+#[derive(Debug)]
+pub struct DecodedTrack {
+    path: PathBuf,
+    format: DecodeFormat,
+
+    /// Format of the audio in the track's file.
+    source_format: SourceFormat,
+
+    /// Interleaved samples.
+    samples: Vec<f32>,
+    replay_gain: ReplayGainTags,
+    duration: Option<Duration>,
+}
+
+impl DecodedTrack {
+    pub(crate) fn new(
+        path: PathBuf,
+        format: DecodeFormat,
+        source_format: SourceFormat,
+        mut samples: Vec<f32>,
+        replay_gain: ReplayGainTags,
+        duration: Option<Duration>,
+    ) -> Self {
+        // room was reserved for the whole track, with some to spare
+        samples.shrink_to_fit();
+        Self {
+            path,
+            format,
+            source_format,
+            samples,
+            replay_gain,
+            duration,
+        }
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    pub fn format(&self) -> DecodeFormat {
+        self.format
+    }
+
+    pub fn source_format(&self) -> SourceFormat {
+        self.source_format
+    }
+
+    pub fn replay_gain(&self) -> &ReplayGainTags {
+        &self.replay_gain
+    }
+
+    /// Length of the track, from its file if known, otherwise from the decoded audio.
+    pub fn duration(&self) -> Option<Duration> {
+        self.duration
+            .or_else(|| Some(self.position_of(self.samples.len())))
+    }
+
+    /// Memory used by the audio, in bytes.
+    pub fn size(&self) -> usize {
+        self.samples.capacity() * std::mem::size_of::<f32>()
+    }
+
+    pub(crate) fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+
+    /// Position in the track of a sample.
+    pub(crate) fn position_of(&self, sample: usize) -> Duration {
+        let frames = sample / self.format.channels() as usize;
+        Duration::from_secs_f64(frames as f64 / self.format.sample_rate().max(1) as f64)
+    }
+
+    /// Index of the first sample of the frame at a position, clamped to the end of the track.
+    pub(crate) fn sample_at(&self, position: Duration) -> usize {
+        let frame = (position.as_secs_f64() * self.format.sample_rate() as f64) as usize;
+        cmp::min(frame * self.format.channels() as usize, self.samples.len())
+    }
+}
+
+/// Memory set aside in a [`DecodedCache`] for a track while it is decoded,
+/// so the cache and the tracks being decoded together stay within its capacity.
+/// Released once dropped.
+#[derive(Debug)]
+pub struct Reservation {
+    size: usize,
+    reserved: Arc<AtomicUsize>,
+}
+
+impl Reservation {
+    /// Bytes set aside.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.reserved.fetch_sub(self.size, Ordering::AcqRel);
+    }
+}
+
+/// Recently decoded tracks, bounded by the memory they use
+/// together with the tracks being decoded for it.
+/// The least recently used tracks are dropped to make room.
+#[derive(Debug)]
+pub struct DecodedCache {
+    /// Most bytes of audio held or reserved.
+    capacity: usize,
+
+    /// Bytes of audio held.
+    size: usize,
+
+    /// Bytes reserved for tracks being decoded, see [`Reservation`].
+    reserved: Arc<AtomicUsize>,
+
+    /// Tracks, from least to most recently used.
+    tracks: VecDeque<Arc<DecodedTrack>>,
+}
+
+impl DecodedCache {
+    /// # Arguments
+    /// + `capacity`: Most bytes of audio to hold. `0` disables the cache.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            size: 0,
+            reserved: Arc::new(AtomicUsize::new(0)),
+            tracks: VecDeque::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Bytes of audio held.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Bytes reserved for tracks being decoded.
+    pub fn reserved(&self) -> usize {
+        self.reserved.load(Ordering::Acquire)
+    }
+
+    /// Number of tracks held.
+    pub fn len(&self) -> usize {
+        self.tracks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tracks.is_empty()
+    }
+
+    /// Whether the track is held, decoded to the given format.
+    pub fn contains(&self, path: &PathBuf, format: DecodeFormat) -> bool {
+        self.tracks
+            .iter()
+            .any(|track| track.path() == path && track.format() == format)
+    }
+
+    /// Format of the audio in the file of a track held, in any format.
+    pub fn source_format(&self, path: &PathBuf) -> Option<SourceFormat> {
+        self.tracks
+            .iter()
+            .find(|track| track.path() == path)
+            .map(|track| track.source_format())
+    }
+
+    /// Gets a track decoded to the given format, marking it as recently used.
+    pub fn get(&mut self, path: &PathBuf, format: DecodeFormat) -> Option<Arc<DecodedTrack>> {
+        let idx = self
+            .tracks
+            .iter()
+            .position(|track| track.path() == path && track.format() == format)?;
+
+        let track = self.tracks.remove(idx)?;
+        self.tracks.push_back(track.clone());
+        Some(track)
+    }
+
+    /// Sets memory aside for a track to be decoded,
+    /// dropping the least recently used tracks until it fits.
+    ///
+    /// # Returns
+    /// `None` if the memory not already reserved is smaller than `size`.
+    pub fn reserve(&mut self, size: usize) -> Option<Reservation> {
+        if size > self.capacity.saturating_sub(self.reserved()) {
+            return None;
+        }
+
+        self.make_room(size);
+        self.reserved.fetch_add(size, Ordering::AcqRel);
+        Some(Reservation {
+            size,
+            reserved: self.reserved.clone(),
+        })
+    }
+
+    /// Adds a track, replacing any held for the same file,
+    /// and dropping the least recently used tracks until it fits.
+    /// Tracks larger than the memory not reserved are not added,
+    /// so the track's own reservation should be dropped first.
+    pub fn insert(&mut self, track: DecodedTrack) {
+        if track.size() > self.capacity.saturating_sub(self.reserved()) {
+            return;
+        }
+
+        self.remove(track.path());
+        self.make_room(track.size());
+        self.size += track.size();
+        self.tracks.push_back(Arc::new(track));
+    }
+
+    /// Drops the least recently used tracks until `size` more bytes fit.
+    fn make_room(&mut self, size: usize) {
+        while self.size + self.reserved() + size > self.capacity {
+            let Some(evicted) = self.tracks.pop_front() else {
+                break;
+            };
+            self.size -= evicted.size();
+            tracing::trace!("{:?} dropped from decoded cache", evicted.path());
+        }
+    }
+
+    /// Removes the track of a file, in any format.
+    pub fn remove(&mut self, path: &PathBuf) {
+        let size = &mut self.size;
+        self.tracks.retain(|track| {
+            let keep = track.path() != path;
+            if !keep {
+                *size -= track.size();
+            }
+            keep
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ffmpeg_next as ffm;
+
+    const TRACK_SIZE: usize = 100 * std::mem::size_of::<f32>();
+
+    fn format(sample_rate: u32) -> DecodeFormat {
+        DecodeFormat {
+            sample_rate,
+            mix_layout: ffm::ChannelLayout::STEREO,
+            device_layout: ffm::ChannelLayout::STEREO,
+            bit_perfect: false,
+        }
+    }
+
+    /// Track of 100 samples.
+    fn track(name: &str) -> DecodedTrack {
+        let source_format = SourceFormat {
+            sample_rate: 48_000,
+            sample_format: ffm::format::Sample::F32(ffm::format::sample::Type::Packed),
+            bits_per_sample: None,
+            channels: 2,
+            channel_layout: ffm::ChannelLayout::STEREO,
+        };
+
+        DecodedTrack::new(
+            PathBuf::from(name),
+            format(48_000),
+            source_format,
+            vec![0.0; 100],
+            ReplayGainTags::default(),
+            None,
+        )
+    }
+
+    fn held(cache: &DecodedCache, names: &[&str]) -> bool {
+        names
+            .iter()
+            .all(|name| cache.contains(&PathBuf::from(name), format(48_000)))
+    }
+
+    #[test]
+    fn least_recently_used_is_evicted() {
+        let mut cache = DecodedCache::new(TRACK_SIZE * 3);
+        cache.insert(track("a"));
+        cache.insert(track("b"));
+        cache.insert(track("c"));
+        assert_eq!(cache.size(), TRACK_SIZE * 3);
+
+        assert!(cache.get(&PathBuf::from("a"), format(48_000)).is_some());
+        cache.insert(track("d"));
+        assert_eq!(cache.len(), 3);
+        assert!(held(&cache, &["a", "c", "d"]));
+        assert!(!held(&cache, &["b"]));
+
+        cache.insert(track("e"));
+        assert!(held(&cache, &["a", "d", "e"]));
+        assert!(!held(&cache, &["c"]));
+    }
+
+    #[test]
+    fn track_is_found_in_its_format() {
+        let mut cache = DecodedCache::new(TRACK_SIZE);
+        cache.insert(track("a"));
+        assert!(cache.get(&PathBuf::from("a"), format(44_100)).is_none());
+        assert!(cache.get(&PathBuf::from("a"), format(48_000)).is_some());
+    }
+
+    #[test]
+    fn same_file_is_replaced() {
+        let mut cache = DecodedCache::new(TRACK_SIZE * 3);
+        cache.insert(track("a"));
+        cache.insert(track("a"));
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.size(), TRACK_SIZE);
+
+        cache.remove(&PathBuf::from("a"));
+        assert!(cache.is_empty());
+        assert_eq!(cache.size(), 0);
+    }
+
+    #[test]
+    fn track_larger_than_cache_is_not_added() {
+        let mut cache = DecodedCache::new(TRACK_SIZE * 2);
+        cache.insert(track("a"));
+
+        cache.insert(DecodedTrack::new(
+            PathBuf::from("b"),
+            format(48_000),
+            track("b").source_format(),
+            vec![0.0; 300],
+            ReplayGainTags::default(),
+            None,
+        ));
+        assert_eq!(cache.len(), 1);
+        assert!(held(&cache, &["a"]));
+
+        let mut disabled = DecodedCache::new(0);
+        disabled.insert(track("a"));
+        assert!(disabled.is_empty());
+    }
+
+    #[test]
+    fn reservation_makes_room_until_dropped() {
+        let mut cache = DecodedCache::new(TRACK_SIZE * 3);
+        cache.insert(track("a"));
+        cache.insert(track("b"));
+
+        let reservation = cache.reserve(TRACK_SIZE * 2).unwrap();
+        assert_eq!(cache.reserved(), TRACK_SIZE * 2);
+        assert!(held(&cache, &["b"]));
+        assert!(!held(&cache, &["a"]));
+
+        // no room left beside the reservation
+        assert!(cache.reserve(TRACK_SIZE * 2).is_none());
+        cache.insert(track("c"));
+        assert!(held(&cache, &["c"]));
+        assert!(!held(&cache, &["b"]));
+
+        drop(reservation);
+        assert_eq!(cache.reserved(), 0);
+        cache.insert(track("d"));
+        cache.insert(track("e"));
+        assert!(held(&cache, &["c", "d", "e"]));
+    }
+}
@@ -0,0 +1,455 @@
+//! Command line arguments.
+use crate::{device, DEFAULT_AUDIO_BUFFER, DEFAULT_CACHE_SIZE, DEFAULT_CROSSFADE};
+use clap::{parser::ValueSource, value_parser, Arg, ArgAction, ArgMatches, Command};
+use ffmpeg_next as ffm;
+use sensit_audio_cli as lib;
+use std::{env, path::PathBuf, time::Duration};
+
+pub const CMD_ANALYZE: &str = "analyze";
+pub const CMD_DEVICES: &str = "devices";
+
+pub const ARG_DIR: &str = "dir";
+pub const ARG_FORCE: &str = "force";
+pub const ARG_CROSSFADE: &str = "crossfade";
+pub const ARG_CROSSFADE_CURVE: &str = "crossfade-curve";
+pub const ARG_NO_SKIP_CROSSFADE: &str = "no-skip-crossfade";
+pub const ARG_CHANNEL_LAYOUT: &str = "channel-layout";
+pub const ARG_REPLAY_GAIN: &str = "replay-gain";
+pub const ARG_REPLAY_GAIN_PREAMP: &str = "replay-gain-preamp";
+pub const ARG_REPLAY_GAIN_ALLOW_CLIPPING: &str = "replay-gain-allow-clipping";
+pub const ARG_TARGET_LOUDNESS: &str = "target-lufs";
+pub const ARG_FILTER: &str = "filter";
+pub const ARG_FADE: &str = "fade";
+pub const ARG_MAX_DECODE_ERRORS: &str = "max-decode-errors";
+pub const ARG_CONFIG: &str = "config";
+pub const ARG_EQ: &str = "eq";
+pub const ARG_BUFFER: &str = "buffer";
+pub const ARG_PERIOD: &str = "period";
+pub const ARG_CACHE: &str = "cache";
+pub const ARG_DEVICE: &str = "device";
+pub const ARG_HOST: &str = "host";
+pub const ARG_SAMPLE_RATE: &str = "sample-rate";
+pub const ARG_CHANNELS: &str = "channels";
+pub const ARG_SAMPLE_FORMAT: &str = "sample-format";
+pub const ARG_BIT_PERFECT: &str = "bit-perfect";
+
+const CROSSFADE_CURVE_LINEAR: &str = "linear";
+const CROSSFADE_CURVE_EQUAL_POWER: &str = "equal-power";
+
+const REPLAY_GAIN_OFF: &str = "off";
+const REPLAY_GAIN_TRACK: &str = "track";
+const REPLAY_GAIN_ALBUM: &str = "album";
+
+/// Names of the layouts that can be forced, and their layouts.
+const CHANNEL_LAYOUTS: [(&str, ffm::ChannelLayout); 8] = [
+    ("mono", ffm::ChannelLayout::MONO),
+    ("stereo", ffm::ChannelLayout::STEREO),
+    ("2.1", ffm::ChannelLayout::_2POINT1),
+    ("3.0", ffm::ChannelLayout::SURROUND),
+    ("quad", ffm::ChannelLayout::QUAD),
+    ("5.0", ffm::ChannelLayout::_5POINT0),
+    ("5.1", ffm::ChannelLayout::_5POINT1),
+    ("7.1", ffm::ChannelLayout::_7POINT1),
+];
+
+/// Names of the sample formats the device can be opened with, and their formats.
+/// Audio is buffered as `f32` and converted to the device's format as it is played,
+/// so 64 bit formats hold nothing more, and are never used for bit-perfect playback.
+const SAMPLE_FORMATS: [(&str, cpal::SampleFormat); 10] = [
+    ("i8", cpal::SampleFormat::I8),
+    ("i16", cpal::SampleFormat::I16),
+    ("i32", cpal::SampleFormat::I32),
+    ("i64", cpal::SampleFormat::I64),
+    ("u8", cpal::SampleFormat::U8),
+    ("u16", cpal::SampleFormat::U16),
+    ("u32", cpal::SampleFormat::U32),
+    ("u64", cpal::SampleFormat::U64),
+    ("f32", cpal::SampleFormat::F32),
+    ("f64", cpal::SampleFormat::F64),
+];
+
+pub fn command() -> Command {
+    Command::new(env!("CARGO_PKG_NAME"))
+        .about("Plays the audio files in a folder.")
+        .args_conflicts_with_subcommands(true)
+        .subcommand(
+            Command::new(CMD_ANALYZE)
+                .about("Measures the loudness of the audio files in a folder.")
+                .arg(dir_arg())
+                .arg(
+                    Arg::new(ARG_FORCE)
+                        .long(ARG_FORCE)
+                        .action(ArgAction::SetTrue)
+                        .help("Analyze files again, even if they were already analyzed."),
+                ),
+        )
+        .subcommand(
+            Command::new(CMD_DEVICES)
+                .about("Lists the output devices, and the configs each supports.")
+                .arg(host_arg()),
+        )
+        .arg(dir_arg())
+        .arg(
+            Arg::new(ARG_CROSSFADE)
+                .long(ARG_CROSSFADE)
+                .value_name("SECONDS")
+                .value_parser(seconds)
+                .help("Crossfade between songs over the given time."),
+        )
+        .arg(
+            Arg::new(ARG_CROSSFADE_CURVE)
+                .long(ARG_CROSSFADE_CURVE)
+                .value_parser([CROSSFADE_CURVE_LINEAR, CROSSFADE_CURVE_EQUAL_POWER])
+                .default_value(CROSSFADE_CURVE_EQUAL_POWER)
+                .help("Shape of the crossfade."),
+        )
+        .arg(
+            Arg::new(ARG_NO_SKIP_CROSSFADE)
+                .long(ARG_NO_SKIP_CROSSFADE)
+                .action(ArgAction::SetTrue)
+                .help("Cut songs off when skipping, rather than crossfading."),
+        )
+        .arg(
+            Arg::new(ARG_CHANNEL_LAYOUT)
+                .long(ARG_CHANNEL_LAYOUT)
+                .value_name("LAYOUT")
+                .value_parser(CHANNEL_LAYOUTS.map(|(name, _)| name))
+                .help("Mix songs to the given layout. [default: layout of the device]"),
+        )
+        .arg(
+            Arg::new(ARG_REPLAY_GAIN)
+                .long(ARG_REPLAY_GAIN)
+                .value_parser([REPLAY_GAIN_OFF, REPLAY_GAIN_TRACK, REPLAY_GAIN_ALBUM])
+                .default_value(REPLAY_GAIN_OFF)
+                .help("Normalize loudness using the songs' ReplayGain tags."),
+        )
+        .arg(
+            Arg::new(ARG_REPLAY_GAIN_PREAMP)
+                .long(ARG_REPLAY_GAIN_PREAMP)
+                .value_name("DB")
+                .value_parser(value_parser!(f32))
+                .allow_negative_numbers(true)
+                .default_value("0")
+                .help("Gain added to the ReplayGain of songs."),
+        )
+        .arg(
+            Arg::new(ARG_REPLAY_GAIN_ALLOW_CLIPPING)
+                .long(ARG_REPLAY_GAIN_ALLOW_CLIPPING)
+                .action(ArgAction::SetTrue)
+                .help("Do not lower the ReplayGain of songs that would clip."),
+        )
+        .arg(
+            Arg::new(ARG_TARGET_LOUDNESS)
+                .long(ARG_TARGET_LOUDNESS)
+                .value_name("LUFS")
+                .value_parser(value_parser!(f32))
+                .allow_negative_numbers(true)
+                .help(
+                    "Normalize songs to the given loudness. \
+                    Songs without ReplayGain tags use their analyzed loudness. \
+                    Turns on track ReplayGain if no mode is given.",
+                ),
+        )
+        .arg(
+            Arg::new(ARG_FILTER)
+                .long(ARG_FILTER)
+                .value_name("FILTERS")
+                .help("Run songs through ffmpeg filters, e.g. `highpass=f=200,acompressor`."),
+        )
+        .arg(
+            Arg::new(ARG_FADE)
+                .long(ARG_FADE)
+                .value_name("MILLISECONDS")
+                .value_parser(value_parser!(u64))
+                .help(format!(
+                    "Fade out before pausing, stopping, or seeking, and in when resuming. [default: {}]",
+                    lib::DEFAULT_PLAYBACK_FADE.as_millis()
+                )),
+        )
+        .arg(
+            Arg::new(ARG_MAX_DECODE_ERRORS)
+                .long(ARG_MAX_DECODE_ERRORS)
+                .value_name("COUNT")
+                .value_parser(value_parser!(u32))
+                .help(format!(
+                    "Corrupt packets skipped before a song is abandoned for the next one. [default: {}]",
+                    lib::DecodeErrorPolicy::default().max_errors
+                )),
+        )
+        .arg(
+            Arg::new(ARG_CONFIG)
+                .long(ARG_CONFIG)
+                .value_name("PATH")
+                .value_parser(value_parser!(PathBuf))
+                .help(
+                    "Settings file. \
+                    [default: $XDG_CONFIG_HOME/sensit-audio-cli/config]",
+                ),
+        )
+        .arg(
+            Arg::new(ARG_EQ)
+                .long(ARG_EQ)
+                .value_name("PRESET")
+                .help("Equalize songs with a preset from the settings file."),
+        )
+        .arg(
+            Arg::new(ARG_BUFFER)
+                .long(ARG_BUFFER)
+                .value_name("MILLISECONDS")
+                .value_parser(value_parser!(u64).range(1..))
+                .help(format!(
+                    "Length of audio buffered ahead of playback. \
+                    Longer buffers ride out slow decoding. [default: {}]",
+                    DEFAULT_AUDIO_BUFFER.as_millis()
+                )),
+        )
+        .arg(
+            Arg::new(ARG_PERIOD)
+                .long(ARG_PERIOD)
+                .value_name("FRAMES")
+                .value_parser(value_parser!(u32).range(1..))
+                .help(
+                    "Ask the device for audio in blocks of the given number of frames, \
+                    e.g. `256` for low latency. [default: chosen by the device]",
+                ),
+        )
+        .arg(
+            Arg::new(ARG_CACHE)
+                .long(ARG_CACHE)
+                .value_name("MEGABYTES")
+                .value_parser(value_parser!(usize))
+                .help(format!(
+                    "Memory used to keep recently decoded songs, \
+                    so they play again without decoding. `0` disables the cache. [default: {}]",
+                    DEFAULT_CACHE_SIZE
+                )),
+        )
+        .arg(
+            Arg::new(ARG_DEVICE)
+                .long(ARG_DEVICE)
+                .value_name("NAME")
+                .help(format!(
+                    "Play through the output device with the given name, see `{CMD_DEVICES}`. \
+                    [default: the default device]"
+                )),
+        )
+        .arg(host_arg())
+        .arg(
+            Arg::new(ARG_SAMPLE_RATE)
+                .long(ARG_SAMPLE_RATE)
+                .value_name("HZ")
+                .value_parser(value_parser!(u32).range(1..))
+                .help(
+                    "Open the device at the given sample rate. \
+                    [default: the song's rate if supported, otherwise the device's]",
+                ),
+        )
+        .arg(
+            Arg::new(ARG_CHANNELS)
+                .long(ARG_CHANNELS)
+                .value_name("COUNT")
+                .value_parser(value_parser!(u16).range(1..))
+                .help("Open the device with the given number of channels. [default: stereo if supported]"),
+        )
+        .arg(
+            Arg::new(ARG_SAMPLE_FORMAT)
+                .long(ARG_SAMPLE_FORMAT)
+                .value_name("FORMAT")
+                .value_parser(SAMPLE_FORMATS.map(|(name, _)| name))
+                .help(
+                    "Open the device with the given sample format. \
+                    Audio is converted to it from `f32`, so 64 bit formats add no precision. \
+                    [default: `f32` if supported]",
+                ),
+        )
+        .arg(
+            Arg::new(ARG_BIT_PERFECT)
+                .long(ARG_BIT_PERFECT)
+                .action(ArgAction::SetTrue)
+                .help(
+                    "Open the device in each song's own sample rate, channels, and format, \
+                    so it plays without being resampled or converted. \
+                    Songs in another format than the last are played with a gap.",
+                ),
+        )
+}
+
+fn host_arg() -> Arg {
+    Arg::new(ARG_HOST)
+        .long(ARG_HOST)
+        .value_name("HOST")
+        .value_parser(device::host_names())
+        .ignore_case(true)
+        .help(
+            "Audio host to play through, e.g. `jack` (needs the `jack` feature). \
+            [default: the system's default host]",
+        )
+}
+
+/// Parses a length of time in seconds, e.g. `2.5`.
+fn seconds(value: &str) -> Result<Duration, String> {
+    let secs = value
+        .parse::<f64>()
+        .map_err(|_| format!("`{value}` is not a number of seconds"))?;
+
+    Duration::try_from_secs_f64(secs).map_err(|_| format!("`{value}` can not be negative"))
+}
+
+fn dir_arg() -> Arg {
+    Arg::new(ARG_DIR)
+        .value_name("PATH")
+        .value_parser(value_parser!(PathBuf))
+        .help("Folder to pull audio files from, recursively. [default: current location]")
+}
+
+/// Folder to pull audio files from.
+pub fn dir(args: &ArgMatches) -> PathBuf {
+    match args.get_one::<PathBuf>(ARG_DIR) {
+        Some(dir) => dir.clone(),
+        None => {
+            tracing::info!("No path provided, using current location.");
+            env::current_dir().expect("can not get current directory")
+        }
+    }
+}
+
+/// # Returns
+/// (crossfade, if crossfading is enabled)
+pub fn crossfade(args: &ArgMatches) -> (lib::CrossfadeConfig, bool) {
+    let duration = args.get_one::<Duration>(ARG_CROSSFADE).copied();
+
+    let curve = match args
+        .get_one::<String>(ARG_CROSSFADE_CURVE)
+        .map(String::as_str)
+    {
+        Some(CROSSFADE_CURVE_LINEAR) => lib::FadeCurve::Linear,
+        _ => lib::FadeCurve::EqualPower,
+    };
+
+    let crossfade = lib::CrossfadeConfig {
+        duration: duration.unwrap_or(DEFAULT_CROSSFADE),
+        curve,
+        on_skip: !args.get_flag(ARG_NO_SKIP_CROSSFADE),
+    };
+
+    (crossfade, duration.is_some())
+}
+
+/// Channel layout to force, if any.
+pub fn channel_layout(args: &ArgMatches) -> Option<ffm::ChannelLayout> {
+    let name = args.get_one::<String>(ARG_CHANNEL_LAYOUT)?;
+    CHANNEL_LAYOUTS
+        .iter()
+        .find_map(|(layout_name, layout)| (*layout_name == name.as_str()).then_some(*layout))
+}
+
+pub fn replay_gain(args: &ArgMatches) -> lib::ReplayGainConfig {
+    let target_loudness = args.get_one::<f32>(ARG_TARGET_LOUDNESS).copied();
+    let mode = match args.get_one::<String>(ARG_REPLAY_GAIN).map(String::as_str) {
+        _ if target_loudness.is_some()
+            && args.value_source(ARG_REPLAY_GAIN) == Some(ValueSource::DefaultValue) =>
+        {
+            lib::ReplayGainMode::Track
+        }
+        Some(REPLAY_GAIN_TRACK) => lib::ReplayGainMode::Track,
+        Some(REPLAY_GAIN_ALBUM) => lib::ReplayGainMode::Album,
+        _ => lib::ReplayGainMode::Off,
+    };
+
+    lib::ReplayGainConfig {
+        mode,
+        preamp: args
+            .get_one::<f32>(ARG_REPLAY_GAIN_PREAMP)
+            .copied()
+            .unwrap_or_default(),
+        target_loudness: target_loudness.unwrap_or(lib::REPLAY_GAIN_REFERENCE as f32),
+        prevent_clipping: !args.get_flag(ARG_REPLAY_GAIN_ALLOW_CLIPPING),
+    }
+}
+
+/// Length of the fades around pausing and resuming, if given.
+pub fn fade(args: &ArgMatches) -> Option<Duration> {
+    args.get_one::<u64>(ARG_FADE)
+        .copied()
+        .map(Duration::from_millis)
+}
+
+pub fn decode_error_policy(args: &ArgMatches) -> lib::DecodeErrorPolicy {
+    match args.get_one::<u32>(ARG_MAX_DECODE_ERRORS) {
+        Some(max_errors) => lib::DecodeErrorPolicy {
+            max_errors: *max_errors,
+        },
+        None => lib::DecodeErrorPolicy::default(),
+    }
+}
+
+/// Length of audio buffered ahead of playback, if given.
+pub fn buffer(args: &ArgMatches) -> Option<Duration> {
+    args.get_one::<u64>(ARG_BUFFER)
+        .copied()
+        .map(Duration::from_millis)
+}
+
+/// Number of frames the device should ask for at a time, if given.
+pub fn period(args: &ArgMatches) -> Option<u32> {
+    args.get_one::<u32>(ARG_PERIOD).copied()
+}
+
+/// Memory used to keep decoded songs, in bytes.
+pub fn cache_size(args: &ArgMatches) -> usize {
+    let megabytes = args
+        .get_one::<usize>(ARG_CACHE)
+        .copied()
+        .unwrap_or(DEFAULT_CACHE_SIZE);
+
+    megabytes.saturating_mul(1024 * 1024)
+}
+
+/// Name of the output device to play through, if given.
+pub fn device(args: &ArgMatches) -> Option<String> {
+    args.get_one::<String>(ARG_DEVICE).cloned()
+}
+
+/// Config the device is opened with, given the sample rate of the audio to be played.
+pub fn config_preference(args: &ArgMatches, native_rate: Option<u32>) -> device::ConfigPreference {
+    let sample_format = args.get_one::<String>(ARG_SAMPLE_FORMAT).and_then(|name| {
+        SAMPLE_FORMATS
+            .iter()
+            .find_map(|(format_name, format)| (*format_name == name.as_str()).then_some(*format))
+    });
+
+    device::ConfigPreference {
+        native_rate,
+        sample_rate: args.get_one::<u32>(ARG_SAMPLE_RATE).copied(),
+        channels: args.get_one::<u16>(ARG_CHANNELS).copied(),
+        sample_format,
+    }
+}
+
+/// Whether to open the device in each song's own format.
+pub fn bit_perfect(args: &ArgMatches) -> bool {
+    args.get_flag(ARG_BIT_PERFECT)
+}
+
+/// Audio host to play through, if given.
+pub fn host(args: &ArgMatches) -> Option<cpal::HostId> {
+    args.get_one::<String>(ARG_HOST)
+        .and_then(|name| device::host_id(name))
+}
+
+/// Settings file to load, if one is given.
+pub fn config(args: &ArgMatches) -> Option<PathBuf> {
+    args.get_one::<PathBuf>(ARG_CONFIG).cloned()
+}
+
+/// Name of the equalizer preset to use, if any.
+pub fn eq(args: &ArgMatches) -> Option<String> {
+    args.get_one::<String>(ARG_EQ).cloned()
+}
+
+/// ffmpeg filter graph to run songs through, if any.
+pub fn filter(args: &ArgMatches) -> Option<String> {
+    args.get_one::<String>(ARG_FILTER)
+        .filter(|filter| !filter.trim().is_empty())
+        .cloned()
+}
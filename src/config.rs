@@ -0,0 +1,326 @@
+//! Settings file.
+//!
+//! # Format
+//! ```text
+//! # comments start with `#`
+//! [output]
+//! buffer = <milliseconds>
+//! period = <frames>
+//! host = <name>
+//!
+//! [eq.<preset>]
+//! band = <kind> <frequency> [gain] [q]
+//! ```
+//! + `buffer`: Length of audio buffered ahead of playback.
+//! + `period`: Number of frames the device asks for at a time.
+//! + `host`: Audio host to play through, e.g. `jack`.
+//! + `kind`: `peak`, `lowshelf`, `highshelf`, `lowpass`, or `highpass`.
+//! + `frequency`: In Hz.
+//! + `gain`: In dB, defaults to `0`.
+//! + `q`: Defaults to `0.707`.
+use crate::device;
+use sensit_audio_cli as lib;
+use std::{env, fs, io, path::Path, path::PathBuf, time::Duration};
+
+const SECTION_OUTPUT: &str = "output";
+const SECTION_EQ: &str = "eq.";
+const KEY_BUFFER: &str = "buffer";
+const KEY_PERIOD: &str = "period";
+const KEY_HOST: &str = "host";
+const KEY_BAND: &str = "band";
+
+/// Named equalizer settings.
+#[derive(Clone, Debug)]
+pub struct EqPreset {
+    pub name: String,
+    pub bands: Vec<lib::EqBand>,
+}
+
+/// Settings of the audio output.
+/// Unset values are left to the command line or defaults.
+#[derive(Default, Debug)]
+pub struct OutputConfig {
+    /// Length of audio buffered ahead of playback.
+    pub buffer: Option<Duration>,
+
+    /// Number of frames the device asks for at a time.
+    pub period: Option<u32>,
+
+    /// Audio host to play through.
+    pub host: Option<cpal::HostId>,
+}
+
+#[derive(Default, Debug)]
+pub struct Config {
+    pub output: OutputConfig,
+
+    /// Equalizer presets, in the order they are defined.
+    pub eq_presets: Vec<EqPreset>,
+}
+
+/// Section of the file settings are being read from.
+enum Section {
+    Output,
+    Eq,
+}
+
+impl Config {
+    /// Loads the settings file.
+    /// A missing file gives the default settings.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, error::Config> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Self::parse(&contents),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(error::Config::Io(err)),
+        }
+    }
+
+    pub fn eq_preset(&self, name: &str) -> Option<&EqPreset> {
+        self.eq_presets.iter().find(|preset| preset.name == name)
+    }
+
+    fn parse(contents: &str) -> Result<Self, error::Config> {
+        let mut config = Self::default();
+        let mut section = None;
+        for (idx, line) in contents.lines().enumerate() {
+            let line_number = idx + 1;
+            let invalid = |message: String| error::Config::Invalid {
+                line: line_number,
+                message,
+            };
+
+            let line = line.split_once('#').map_or(line, |(line, _)| line).trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(header) = line
+                .strip_prefix('[')
+                .and_then(|line| line.strip_suffix(']'))
+            {
+                let name = header.trim();
+                if name == SECTION_OUTPUT {
+                    let _ = section.insert(Section::Output);
+                    continue;
+                }
+
+                let Some(name) = name.strip_prefix(SECTION_EQ) else {
+                    return Err(invalid(format!("unknown section `{name}`")));
+                };
+
+                let name = name.trim();
+                if name.is_empty() {
+                    return Err(invalid("equalizer preset has no name".to_string()));
+                }
+                if config.eq_preset(name).is_some() {
+                    return Err(invalid(format!(
+                        "equalizer preset `{name}` is defined twice"
+                    )));
+                }
+
+                config.eq_presets.push(EqPreset {
+                    name: name.to_string(),
+                    bands: Vec::new(),
+                });
+                let _ = section.insert(Section::Eq);
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(invalid(format!("expected `key = value`, found `{line}`")));
+            };
+
+            let (key, value) = (key.trim(), value.trim());
+            match (&section, key) {
+                (Some(Section::Output), KEY_BUFFER) => {
+                    let buffer = value
+                        .trim_end_matches("ms")
+                        .trim()
+                        .parse::<u64>()
+                        .ok()
+                        .filter(|buffer| *buffer > 0)
+                        .ok_or_else(|| {
+                            invalid(format!("invalid buffer `{value}`, expected milliseconds"))
+                        })?;
+
+                    let _ = config.output.buffer.insert(Duration::from_millis(buffer));
+                }
+                (Some(Section::Output), KEY_PERIOD) => {
+                    let period = value
+                        .parse::<u32>()
+                        .ok()
+                        .filter(|period| *period > 0)
+                        .ok_or_else(|| {
+                            invalid(format!("invalid period `{value}`, expected frames"))
+                        })?;
+
+                    let _ = config.output.period.insert(period);
+                }
+                (Some(Section::Output), KEY_HOST) => {
+                    let host = device::host_id(value).ok_or_else(|| {
+                        invalid(format!(
+                            "unknown host `{value}`, expected one of {}",
+                            device::host_names().join(", ")
+                        ))
+                    })?;
+
+                    let _ = config.output.host.insert(host);
+                }
+                (Some(Section::Eq), KEY_BAND) => {
+                    let Some(preset) = config.eq_presets.last_mut() else {
+                        unreachable!("preset is added with its section");
+                    };
+
+                    let band = band_from_str(value).ok_or_else(|| {
+                        invalid(format!(
+                            "invalid band `{value}`, expected `<kind> <frequency> [gain] [q]`"
+                        ))
+                    })?;
+
+                    preset.bands.push(band);
+                }
+                _ => return Err(invalid(format!("unknown key `{key}`"))),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// File settings are loaded from if none is given.
+pub fn default_path() -> Option<PathBuf> {
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+    Some(config_dir.join(env!("CARGO_PKG_NAME")).join("config"))
+}
+
+/// Parses a band of the form `<kind> <frequency> [gain] [q]`.
+fn band_from_str(input: &str) -> Option<lib::EqBand> {
+    let mut values = input.split_whitespace();
+    let kind = match values.next()? {
+        "peak" => lib::EqBandKind::Peak,
+        "lowshelf" => lib::EqBandKind::LowShelf,
+        "highshelf" => lib::EqBandKind::HighShelf,
+        "lowpass" => lib::EqBandKind::LowPass,
+        "highpass" => lib::EqBandKind::HighPass,
+        _ => return None,
+    };
+
+    let frequency = values.next()?.trim_end_matches("Hz").parse::<f32>().ok()?;
+    let gain = match values.next() {
+        Some(gain) => gain.trim_end_matches("dB").parse::<f32>().ok()?,
+        None => 0.0,
+    };
+    let q = match values.next() {
+        Some(q) => q.parse::<f32>().ok()?,
+        None => lib::EqBand::DEFAULT_Q,
+    };
+
+    let valid =
+        frequency.is_finite() && frequency > 0.0 && gain.is_finite() && q.is_finite() && q > 0.0;
+
+    if values.next().is_some() || !valid {
+        return None;
+    }
+
+    Some(lib::EqBand {
+        kind,
+        frequency,
+        gain,
+        q,
+    })
+}
+
+pub mod error {
+    use std::io;
+
+    #[derive(thiserror::Error, Debug)]
+    pub enum Config {
+        #[error("could not read config: {0}")]
+        Io(io::Error),
+
+        #[error("invalid config on line {line}: {message}")]
+        Invalid { line: usize, message: String },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Line of the error of invalid settings.
+    fn invalid_line(contents: &str) -> usize {
+        match Config::parse(contents) {
+            Err(error::Config::Invalid { line, .. }) => line,
+            res => panic!("expected invalid config, found {res:?}"),
+        }
+    }
+
+    #[test]
+    fn settings_are_read_from_their_sections() {
+        let config = Config::parse(
+            "# settings\n\
+            [output]\n\
+            buffer = 250ms\n\
+            period = 512 # frames\n\
+            \n\
+            [eq.bass]\n\
+            band = lowshelf 100 6\n\
+            band = peak 1000Hz -3dB 1.4\n",
+        )
+        .unwrap();
+
+        assert_eq!(config.output.buffer, Some(Duration::from_millis(250)));
+        assert_eq!(config.output.period, Some(512));
+
+        let preset = config.eq_preset("bass").unwrap();
+        assert_eq!(preset.bands.len(), 2);
+        assert_eq!(preset.bands[1].kind, lib::EqBandKind::Peak);
+        assert_eq!(preset.bands[1].gain, -3.0);
+    }
+
+    #[test]
+    fn malformed_line_is_invalid() {
+        assert_eq!(invalid_line("[output]\nbuffer 250\n"), 2);
+        assert_eq!(invalid_line("[output]\nbuffer = -1\n"), 2);
+        assert_eq!(invalid_line("[eq.bass]\nband = loud 100\n"), 2);
+    }
+
+    #[test]
+    fn unknown_key_is_invalid() {
+        assert_eq!(invalid_line("[output]\nvolume = 10\n"), 2);
+        assert_eq!(invalid_line("[eq.bass]\nbuffer = 250\n"), 2);
+
+        // keys belong to a section
+        assert_eq!(invalid_line("buffer = 250\n"), 1);
+    }
+
+    #[test]
+    fn unknown_section_is_invalid() {
+        assert_eq!(invalid_line("[output]\n[input]\n"), 2);
+        assert_eq!(invalid_line("[eq.]\n"), 1);
+        assert_eq!(invalid_line("[eq.bass]\n[eq.bass]\n"), 2);
+    }
+
+    #[test]
+    fn band_takes_defaults() {
+        let band = band_from_str("highpass 80").unwrap();
+        assert_eq!(band.kind, lib::EqBandKind::HighPass);
+        assert_eq!(band.frequency, 80.0);
+        assert_eq!(band.gain, 0.0);
+        assert_eq!(band.q, lib::EqBand::DEFAULT_Q);
+    }
+
+    #[test]
+    fn invalid_band_is_rejected() {
+        assert!(band_from_str("").is_none());
+        assert!(band_from_str("notch 100").is_none());
+        assert!(band_from_str("peak").is_none());
+        assert!(band_from_str("peak 0").is_none());
+        assert!(band_from_str("peak 100 loud").is_none());
+        assert!(band_from_str("peak 100 3 0").is_none());
+        assert!(band_from_str("peak 100 3 1 2").is_none());
+    }
+}
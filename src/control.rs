@@ -0,0 +1,105 @@
+//! Controlling a stream from other threads.
+use crate::{Seek, StreamState};
+use crossbeam::channel;
+use std::sync::{Arc, Mutex};
+
+/// Handle to play, pause, stop, and seek a stream from another thread.
+///
+/// Changes wake the thread feeding the stream's [`AudioOutput`](crate::AudioOutput),
+/// so they take effect immediately rather than once it next checks.
+#[derive(Clone, Debug)]
+pub struct StreamControl {
+    controls: Arc<Mutex<Controls>>,
+
+    /// Wakes the thread feeding the output.
+    wake: channel::Sender<()>,
+}
+
+#[derive(Debug)]
+struct Controls {
+    state: StreamState,
+
+    /// Seek not yet applied to the stream.
+    seek: Option<Seek>,
+}
+
+impl StreamControl {
+    /// Control of a paused stream.
+    pub(crate) fn new(wake: channel::Sender<()>) -> Self {
+        Self {
+            controls: Arc::new(Mutex::new(Controls {
+                state: StreamState::Pause,
+                seek: None,
+            })),
+            wake,
+        }
+    }
+
+    pub fn state(&self) -> StreamState {
+        self.controls.lock().unwrap().state
+    }
+
+    pub fn set_state(&self, state: StreamState) {
+        self.controls.lock().unwrap().state = state;
+        self.notify();
+    }
+
+    pub fn play(&self) {
+        self.set_state(StreamState::Play);
+    }
+
+    pub fn pause(&self) {
+        self.set_state(StreamState::Pause);
+    }
+
+    /// Stops the stream, unless it already loaded all the way through.
+    pub fn stop(&self) {
+        let mut controls = self.controls.lock().unwrap();
+        if !controls.state.is_done() {
+            controls.state = StreamState::Stop;
+            drop(controls);
+            self.notify();
+        }
+    }
+
+    /// Pauses a playing stream, otherwise plays it.
+    ///
+    /// # Returns
+    /// State of the stream after toggling.
+    pub fn toggle_play(&self) -> StreamState {
+        let mut controls = self.controls.lock().unwrap();
+        controls.state = if controls.state.is_playing() {
+            StreamState::Pause
+        } else {
+            StreamState::Play
+        };
+        let state = controls.state;
+        drop(controls);
+
+        self.notify();
+        state
+    }
+
+    /// Requests a seek, replacing any seek not yet applied.
+    pub fn seek(&self, seek: Seek) {
+        let _ = self.controls.lock().unwrap().seek.insert(seek);
+        self.notify();
+    }
+
+    /// Takes the requested seek, if any.
+    pub(crate) fn take_seek(&self) -> Option<Seek> {
+        self.controls.lock().unwrap().seek.take()
+    }
+
+    /// The stream should stop feeding its output:
+    /// it is no longer playing, or a seek is waiting to be applied.
+    pub(crate) fn is_interrupted(&self) -> bool {
+        let controls = self.controls.lock().unwrap();
+        !controls.state.is_playing() || controls.seek.is_some()
+    }
+
+    fn notify(&self) {
+        // a wake already pending is enough
+        let _ = self.wake.try_send(());
+    }
+}
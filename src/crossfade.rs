@@ -0,0 +1,241 @@
+//! Mixing from one track into the next.
+use crate::{AudioOutput, AudioStream};
+use std::{cmp, time::Duration};
+
+#[derive(Clone, Copy, Debug)]
+pub struct CrossfadeConfig {
+    /// Length of the fade.
+    pub duration: Duration,
+    pub curve: FadeCurve,
+
+    /// Also crossfade when a track is changed manually,
+    /// rather than cutting it off.
+    pub on_skip: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum FadeCurve {
+    Linear,
+
+    /// Keeps the combined power constant through the fade.
+    EqualPower,
+}
+
+impl FadeCurve {
+    /// Gains of the outgoing and incoming audio at a point in the fade.
+    ///
+    /// # Arguments
+    /// + `progress`: Position in the fade, from `0` to `1`.
+    ///
+    /// # Returns
+    /// (outgoing gain, incoming gain)
+    pub fn gains(&self, progress: f32) -> (f32, f32) {
+        let progress = progress.clamp(0.0, 1.0);
+        match self {
+            Self::Linear => (1.0 - progress, progress),
+            Self::EqualPower => {
+                let angle = progress * std::f32::consts::FRAC_PI_2;
+                (angle.cos(), angle.sin())
+            }
+        }
+    }
+}
+
+/// Fade from an outgoing stream into the stream loading after it.
+/// Audio of the outgoing stream is decoded as the incoming stream needs it.
+pub struct Crossfade {
+    outgoing: AudioStream,
+    curve: FadeCurve,
+
+    /// Length of the fade in frames.
+    length: usize,
+
+    /// Frames of the fade already mixed.
+    progress: usize,
+
+    /// Decoded outgoing samples not yet mixed.
+    pending: Vec<f32>,
+
+    /// Outgoing stream reached its end.
+    ended: bool,
+}
+
+impl Crossfade {
+    pub fn new(outgoing: AudioStream, config: &CrossfadeConfig, output: &AudioOutput) -> Self {
+        let length = (config.duration.as_secs_f64() * output.sample_rate() as f64) as usize;
+        Self {
+            outgoing,
+            curve: config.curve,
+            length,
+            progress: 0,
+            pending: Vec::new(),
+            ended: false,
+        }
+    }
+
+    /// The outgoing stream has faded out completely.
+    pub fn is_done(&self) -> bool {
+        self.progress >= self.length || (self.ended && self.pending.is_empty())
+    }
+
+    /// The stream fading out.
+    pub fn outgoing(&self) -> &AudioStream {
+        &self.outgoing
+    }
+
+    /// Takes back the stream fading out, e.g. once it has faded out.
+    pub fn into_outgoing(self) -> AudioStream {
+        self.outgoing
+    }
+
+    /// Mixes outgoing audio into the incoming samples.
+    ///
+    /// # Arguments
+    /// + `incoming`: Interleaved samples of the incoming stream.
+    /// + `channels`: Number of channels the samples are interleaved over.
+    pub(crate) fn mix(&mut self, incoming: &mut [f32], channels: u16) {
+        if self.is_done() {
+            return;
+        }
+
+        while self.pending.len() < incoming.len() && !self.ended {
+            match self.outgoing.decode_packet(&mut self.pending) {
+                Ok(true) => {}
+                Ok(false) => self.ended = true,
+                Err(err) => {
+                    // only the outgoing track is affected, so cut it short
+                    tracing::debug!(?err);
+                    self.ended = true;
+                }
+            }
+        }
+
+        if self.ended && self.pending.is_empty() {
+            // nothing left to fade out, play incoming at full volume
+            self.progress = self.length;
+            return;
+        }
+
+        let channels = channels.max(1) as usize;
+        self.progress = mix_samples(
+            incoming,
+            &self.pending,
+            channels,
+            self.curve,
+            self.progress,
+            self.length,
+        );
+
+        let mixed = incoming.len().min(self.pending.len());
+        self.pending.drain(..mixed);
+    }
+}
+
+/// Mixes decoded outgoing samples into the incoming samples.
+///
+/// If the outgoing samples end part way through, the incoming samples are brought
+/// up to full volume over the frames left, rather than jumping to it on the next call.
+///
+/// # Arguments
+/// + `channels`: Number of channels the samples are interleaved over.
+/// + `progress`: Frames of the fade already mixed.
+/// + `length`: Length of the fade in frames.
+///
+/// # Returns
+/// Frames of the fade mixed after these samples.
+fn mix_samples(
+    incoming: &mut [f32],
+    outgoing: &[f32],
+    channels: usize,
+    curve: FadeCurve,
+    progress: usize,
+    length: usize,
+) -> usize {
+    let frames = incoming.len() / channels;
+    let outgoing_frames = (outgoing.len() / channels).min(frames);
+    let tail = frames - outgoing_frames;
+    let tail_start = curve
+        .gains((progress + outgoing_frames) as f32 / length as f32)
+        .1;
+
+    for (idx, sample) in incoming.iter_mut().enumerate() {
+        let frame = idx / channels;
+        let (gain_out, gain_in) = if frame < outgoing_frames {
+            curve.gains((progress + frame) as f32 / length as f32)
+        } else {
+            let ramp = (frame - outgoing_frames + 1) as f32 / tail as f32;
+            (0.0, tail_start + (1.0 - tail_start) * ramp)
+        };
+        let outgoing = outgoing.get(idx).copied().unwrap_or(0.0);
+        *sample = *sample * gain_in + outgoing * gain_out;
+    }
+
+    if tail > 0 {
+        // incoming is at full volume
+        return cmp::max(progress + frames, length);
+    }
+
+    progress + frames
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::FRAC_1_SQRT_2;
+
+    #[test]
+    fn linear_gains_sum_to_one() {
+        assert_eq!(FadeCurve::Linear.gains(0.0), (1.0, 0.0));
+        assert_eq!(FadeCurve::Linear.gains(0.5), (0.5, 0.5));
+        assert_eq!(FadeCurve::Linear.gains(1.0), (0.0, 1.0));
+    }
+
+    #[test]
+    fn equal_power_gains_keep_power() {
+        for (progress, expected) in [
+            (0.0, (1.0, 0.0)),
+            (0.5, (FRAC_1_SQRT_2, FRAC_1_SQRT_2)),
+            (1.0, (0.0, 1.0)),
+        ] {
+            let (gain_out, gain_in) = FadeCurve::EqualPower.gains(progress);
+            assert!((gain_out - expected.0).abs() < 1e-6, "{gain_out}");
+            assert!((gain_in - expected.1).abs() < 1e-6, "{gain_in}");
+            assert!((gain_out.powi(2) + gain_in.powi(2) - 1.0).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn progress_is_clamped() {
+        for curve in [FadeCurve::Linear, FadeCurve::EqualPower] {
+            assert_eq!(curve.gains(-1.0), curve.gains(0.0));
+            assert_eq!(curve.gains(2.0), curve.gains(1.0));
+        }
+    }
+
+    #[test]
+    fn incoming_ramps_up_when_outgoing_runs_out() {
+        let mut incoming = [1.0; 8];
+        let progress = mix_samples(&mut incoming, &[0.5; 2], 1, FadeCurve::Linear, 0, 100);
+        assert_eq!(progress, 100);
+
+        // outgoing is mixed in along the fade
+        assert_eq!(incoming[0], 0.5);
+        assert!((incoming[1] - (0.01 + 0.5 * 0.99)).abs() < 1e-6);
+
+        // then incoming ramps up to full volume by the end of the samples
+        assert!(
+            incoming[2..].windows(2).all(|pair| pair[0] < pair[1]),
+            "{incoming:?}"
+        );
+        assert!((incoming[7] - 1.0).abs() < 1e-6, "{incoming:?}");
+    }
+
+    #[test]
+    fn fade_continues_while_outgoing_lasts() {
+        let mut incoming = [1.0; 8];
+        let progress = mix_samples(&mut incoming, &[0.0; 16], 2, FadeCurve::Linear, 10, 100);
+        assert_eq!(progress, 14);
+        assert_eq!(incoming[0], 0.1);
+        assert_eq!(incoming[7], 0.13);
+    }
+}
@@ -0,0 +1,389 @@
+//! Finding output devices to play through.
+use cpal::traits::*;
+use std::io::{self, Write};
+
+/// Sample rates tried, in order, when none preferred are supported.
+const COMMON_RATES: [u32; 2] = [48_000, 44_100];
+
+/// What to look for in the config a device is opened with.
+/// Required values rule out configs without them,
+/// otherwise configs are preferred by the audio they play without conversion,
+/// see [`stream_config`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConfigPreference {
+    /// Sample rate of the audio to be played, so it is not resampled if the device supports it.
+    pub native_rate: Option<u32>,
+
+    /// Sample rate the device must be opened at.
+    pub sample_rate: Option<u32>,
+
+    /// Number of channels the device must be opened with.
+    pub channels: Option<u16>,
+
+    /// Sample format the device must be opened with.
+    pub sample_format: Option<cpal::SampleFormat>,
+}
+
+impl ConfigPreference {
+    /// Whether the config has the required values.
+    fn allows(&self, config: &cpal::SupportedStreamConfig) -> bool {
+        self.sample_rate
+            .is_none_or(|rate| rate == config.sample_rate().0)
+            && self
+                .channels
+                .is_none_or(|channels| channels == config.channels())
+            && self
+                .sample_format
+                .is_none_or(|format| format == config.sample_format())
+    }
+
+    /// Picks the sample rate to open a range of configs at,
+    /// or `None` if the range does not have the required values.
+    ///
+    /// # Arguments
+    /// + `default`: Device's default config.
+    fn pick_rate(
+        &self,
+        range: &cpal::SupportedStreamConfigRange,
+        default: Option<&cpal::SupportedStreamConfig>,
+    ) -> Option<u32> {
+        let required = self
+            .channels
+            .is_none_or(|channels| channels == range.channels())
+            && self
+                .sample_format
+                .is_none_or(|format| format == range.sample_format());
+        if !required {
+            return None;
+        }
+
+        let (min, max) = (range.min_sample_rate().0, range.max_sample_rate().0);
+        if let Some(rate) = self.sample_rate {
+            return (min..=max).contains(&rate).then_some(rate);
+        }
+
+        self.native_rate
+            .into_iter()
+            .chain(default.map(|config| config.sample_rate().0))
+            .chain(COMMON_RATES)
+            .find(|rate| (min..=max).contains(rate))
+            .or(Some(max))
+    }
+}
+
+/// Names of the hosts that can be selected, e.g. `ALSA`.
+/// Only hosts built in are included, e.g. `JACK` needs the `jack` feature.
+pub fn host_names() -> Vec<&'static str> {
+    cpal::ALL_HOSTS.iter().map(|id| id.name()).collect()
+}
+
+/// Host with the given name, ignoring case.
+pub fn host_id(name: &str) -> Option<cpal::HostId> {
+    cpal::ALL_HOSTS
+        .iter()
+        .copied()
+        .find(|id| id.name().eq_ignore_ascii_case(name))
+}
+
+/// # Arguments
+/// + `id`: Host to use, the default host if `None`.
+///
+/// # Returns
+/// + `Err` if the host can not be used, e.g. the JACK server is not running.
+pub fn host(id: Option<cpal::HostId>) -> Result<cpal::Host, cpal::HostUnavailable> {
+    match id {
+        Some(id) => cpal::host_from_id(id),
+        None => Ok(cpal::default_host()),
+    }
+}
+
+/// Default output device of the host, with the config to open it with.
+pub fn default_output(
+    host: &cpal::Host,
+    preference: &ConfigPreference,
+) -> Option<(cpal::Device, cpal::SupportedStreamConfig)> {
+    let device = host.default_output_device()?;
+    let config = stream_config(&device, preference)?;
+    Some((device, config))
+}
+
+/// Output device of the host with the given name, if it is connected.
+pub fn find_output(host: &cpal::Host, name: &str) -> Option<cpal::Device> {
+    host.output_devices()
+        .ok()?
+        .find(|device| device.name().is_ok_and(|device_name| device_name == name))
+}
+
+/// Config to open the device's output stream with.
+/// Of the configs with the required values, prefers in order
+/// 1. the native sample rate of the audio, so it is not resampled,
+/// 2. `f32` samples, which audio is decoded to,
+/// 3. stereo,
+/// 4. the sample rate, format, and channels of the device's default config.
+///
+/// # Returns
+/// `None` if the device supports no config with the required values.
+pub fn stream_config(
+    device: &cpal::Device,
+    preference: &ConfigPreference,
+) -> Option<cpal::SupportedStreamConfig> {
+    let default = device.default_output_config().ok();
+    let ranges = match device.supported_output_configs() {
+        Ok(ranges) => ranges.collect::<Vec<_>>(),
+        Err(err) => {
+            tracing::debug!(?err);
+            Vec::new()
+        }
+    };
+
+    best_config(&ranges, default, preference)
+}
+
+/// Picks the config to open a device with, see [`stream_config`].
+///
+/// # Arguments
+/// + `ranges`: Configs the device supports.
+/// + `default`: Device's default config.
+fn best_config(
+    ranges: &[cpal::SupportedStreamConfigRange],
+    default: Option<cpal::SupportedStreamConfig>,
+    preference: &ConfigPreference,
+) -> Option<cpal::SupportedStreamConfig> {
+    let default_rate = default.as_ref().map(|config| config.sample_rate().0);
+    let default_format = default.as_ref().map(|config| config.sample_format());
+    let default_channels = default.as_ref().map(|config| config.channels());
+    let score = |config: &cpal::SupportedStreamConfig| {
+        let rate = config.sample_rate().0;
+        (
+            preference.native_rate == Some(rate),
+            config.sample_format() == cpal::SampleFormat::F32,
+            config.channels() == 2,
+            default_rate == Some(rate),
+            default_format == Some(config.sample_format()),
+            default_channels == Some(config.channels()),
+        )
+    };
+
+    // reversed so the first of equally preferred configs is picked
+    let best = ranges
+        .iter()
+        .rev()
+        .filter_map(|range| {
+            let rate = preference.pick_rate(range, default.as_ref())?;
+            range.try_with_sample_rate(cpal::SampleRate(rate))
+        })
+        .max_by_key(score);
+
+    best.or_else(|| default.filter(|config| preference.allows(config)))
+}
+
+/// Prints the output devices of the host, and the stream configs each supports.
+/// The default device is marked with a `*`.
+pub fn list(host: &cpal::Host) {
+    let default = host
+        .default_output_device()
+        .and_then(|device| device.name().ok());
+    let devices = match host.output_devices() {
+        Ok(devices) => devices,
+        Err(err) => {
+            tracing::error!(?err);
+            return;
+        }
+    };
+
+    let mut stdout = io::stdout();
+    for device in devices {
+        let Ok(name) = device.name() else {
+            continue;
+        };
+        if default.as_ref() == Some(&name) {
+            write_trace!(stdout, "* {name}\n");
+        } else {
+            write_trace!(stdout, "  {name}\n");
+        }
+
+        let configs = match device.supported_output_configs() {
+            Ok(configs) => configs,
+            Err(err) => {
+                tracing::debug!(?err);
+                write_trace!(stdout, "    could not query configs\n");
+                continue;
+            }
+        };
+        for config in configs {
+            let (min_rate, max_rate) = (config.min_sample_rate().0, config.max_sample_rate().0);
+            let rates = if min_rate == max_rate {
+                format!("{min_rate} Hz")
+            } else {
+                format!("{min_rate}-{max_rate} Hz")
+            };
+            let period = match config.buffer_size() {
+                cpal::SupportedBufferSize::Range { min, max } => {
+                    format!(", period {min}-{max} frames")
+                }
+                cpal::SupportedBufferSize::Unknown => String::new(),
+            };
+            write_trace!(
+                stdout,
+                "    {} ch, {rates}, {}{period}\n",
+                config.channels(),
+                config.sample_format()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpal::SampleFormat::{F32, I16};
+
+    fn range(
+        channels: u16,
+        rates: (u32, u32),
+        format: cpal::SampleFormat,
+    ) -> cpal::SupportedStreamConfigRange {
+        cpal::SupportedStreamConfigRange::new(
+            channels,
+            cpal::SampleRate(rates.0),
+            cpal::SampleRate(rates.1),
+            cpal::SupportedBufferSize::Unknown,
+            format,
+        )
+    }
+
+    fn config(channels: u16, rate: u32, format: cpal::SampleFormat) -> cpal::SupportedStreamConfig {
+        cpal::SupportedStreamConfig::new(
+            channels,
+            cpal::SampleRate(rate),
+            cpal::SupportedBufferSize::Unknown,
+            format,
+        )
+    }
+
+    /// Channels, sample rate, and sample format of the best config.
+    fn best(
+        ranges: &[cpal::SupportedStreamConfigRange],
+        default: Option<cpal::SupportedStreamConfig>,
+        preference: &ConfigPreference,
+    ) -> Option<(u16, u32, cpal::SampleFormat)> {
+        best_config(ranges, default, preference).map(|config| {
+            (
+                config.channels(),
+                config.sample_rate().0,
+                config.sample_format(),
+            )
+        })
+    }
+
+    #[test]
+    fn native_rate_is_preferred_over_f32() {
+        let ranges = [
+            range(2, (48_000, 48_000), F32),
+            range(2, (44_100, 44_100), I16),
+        ];
+        let preference = ConfigPreference {
+            native_rate: Some(44_100),
+            ..Default::default()
+        };
+
+        assert_eq!(best(&ranges, None, &preference), Some((2, 44_100, I16)));
+        assert_eq!(
+            best(&ranges, None, &ConfigPreference::default()),
+            Some((2, 48_000, F32))
+        );
+    }
+
+    #[test]
+    fn f32_is_preferred_over_stereo() {
+        let ranges = [
+            range(2, (48_000, 48_000), I16),
+            range(6, (48_000, 48_000), F32),
+        ];
+        assert_eq!(
+            best(&ranges, None, &ConfigPreference::default()),
+            Some((6, 48_000, F32))
+        );
+    }
+
+    #[test]
+    fn stereo_is_preferred_over_defaults() {
+        let ranges = [
+            range(1, (48_000, 48_000), F32),
+            range(2, (48_000, 48_000), F32),
+        ];
+        let default = config(1, 48_000, F32);
+        assert_eq!(
+            best(&ranges, Some(default), &ConfigPreference::default()),
+            Some((2, 48_000, F32))
+        );
+    }
+
+    #[test]
+    fn defaults_break_ties() {
+        let ranges = [range(2, (44_100, 48_000), F32)];
+        let default = config(2, 44_100, F32);
+        assert_eq!(
+            best(&ranges, Some(default.clone()), &ConfigPreference::default()),
+            Some((2, 44_100, F32))
+        );
+
+        // the native rate is not supported
+        let preference = ConfigPreference {
+            native_rate: Some(96_000),
+            ..Default::default()
+        };
+        assert_eq!(
+            best(&ranges, Some(default), &preference),
+            Some((2, 44_100, F32))
+        );
+
+        // common rates are tried without a default
+        assert_eq!(
+            best(&ranges, None, &ConfigPreference::default()),
+            Some((2, 48_000, F32))
+        );
+    }
+
+    #[test]
+    fn required_values_rule_out_configs() {
+        let ranges = [
+            range(2, (44_100, 48_000), F32),
+            range(1, (8_000, 96_000), I16),
+        ];
+        let preference = ConfigPreference {
+            channels: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(best(&ranges, None, &preference), Some((1, 48_000, I16)));
+
+        let preference = ConfigPreference {
+            native_rate: Some(44_100),
+            sample_rate: Some(96_000),
+            ..Default::default()
+        };
+        assert_eq!(best(&ranges, None, &preference), Some((1, 96_000, I16)));
+
+        let preference = ConfigPreference {
+            sample_format: Some(I16),
+            channels: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(best(&ranges, None, &preference), None);
+    }
+
+    #[test]
+    fn default_is_used_if_allowed_when_no_range_fits() {
+        let default = config(2, 48_000, F32);
+        assert_eq!(
+            best(&[], Some(default.clone()), &ConfigPreference::default()),
+            Some((2, 48_000, F32))
+        );
+
+        let preference = ConfigPreference {
+            sample_rate: Some(44_100),
+            ..Default::default()
+        };
+        assert_eq!(best(&[], Some(default), &preference), None);
+    }
+}
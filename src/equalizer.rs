@@ -0,0 +1,207 @@
+//! Parametric equalizer.
+use crate::biquad::Biquad;
+use std::f64::consts::PI;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EqBandKind {
+    /// Boosts or cuts around the frequency.
+    Peak,
+
+    /// Boosts or cuts below the frequency.
+    LowShelf,
+
+    /// Boosts or cuts above the frequency.
+    HighShelf,
+
+    /// Removes audio above the frequency.
+    LowPass,
+
+    /// Removes audio below the frequency.
+    HighPass,
+}
+
+/// Band of a parametric equalizer.
+#[derive(Clone, Copy, Debug)]
+pub struct EqBand {
+    pub kind: EqBandKind,
+
+    /// Center or corner frequency, in Hz.
+    pub frequency: f32,
+
+    /// Boost, or cut if negative, in dB.
+    /// Unused by low and high passes.
+    pub gain: f32,
+
+    /// Sharpness of the band.
+    pub q: f32,
+}
+
+impl EqBand {
+    /// Q of a band with no resonance, used if none is given.
+    pub const DEFAULT_Q: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+    /// Filter of the band, following the RBJ audio EQ cookbook.
+    fn biquad(&self, sample_rate: u32) -> Biquad {
+        let sample_rate = sample_rate as f64;
+        let frequency = (self.frequency as f64).clamp(1.0, sample_rate * 0.49);
+        let w0 = 2.0 * PI * frequency / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * (self.q as f64).max(0.01));
+        let a = 10_f64.powf(self.gain as f64 / 40.0);
+        let shelf = 2.0 * a.sqrt() * alpha;
+
+        let (b, den) = match self.kind {
+            EqBandKind::Peak => (
+                [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+                [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+            ),
+            EqBandKind::LowShelf => (
+                [
+                    a * ((a + 1.0) - (a - 1.0) * cos + shelf),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                    a * ((a + 1.0) - (a - 1.0) * cos - shelf),
+                ],
+                [
+                    (a + 1.0) + (a - 1.0) * cos + shelf,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                    (a + 1.0) + (a - 1.0) * cos - shelf,
+                ],
+            ),
+            EqBandKind::HighShelf => (
+                [
+                    a * ((a + 1.0) + (a - 1.0) * cos + shelf),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                    a * ((a + 1.0) + (a - 1.0) * cos - shelf),
+                ],
+                [
+                    (a + 1.0) - (a - 1.0) * cos + shelf,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                    (a + 1.0) - (a - 1.0) * cos - shelf,
+                ],
+            ),
+            EqBandKind::LowPass => (
+                [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+                [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            ),
+            EqBandKind::HighPass => (
+                [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+                [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            ),
+        };
+
+        Biquad::new(
+            [b[0] / den[0], b[1] / den[0], b[2] / den[0]],
+            [den[1] / den[0], den[2] / den[0]],
+        )
+    }
+}
+
+/// Runs interleaved audio through the bands of an equalizer.
+pub(crate) struct Equalizer {
+    /// Filters of each band, for each channel.
+    filters: Vec<Vec<Biquad>>,
+}
+
+impl Equalizer {
+    pub fn new(bands: &[EqBand], sample_rate: u32, channels: u16) -> Self {
+        let filters = bands
+            .iter()
+            .map(|band| band.biquad(sample_rate))
+            .collect::<Vec<_>>();
+
+        Self {
+            filters: vec![filters; channels.max(1) as usize],
+        }
+    }
+
+    pub fn process(&mut self, samples: &mut [f32]) {
+        let channels = self.filters.len();
+        for frame in samples.chunks_exact_mut(channels) {
+            for (sample, filters) in frame.iter_mut().zip(self.filters.iter_mut()) {
+                *sample = filters
+                    .iter_mut()
+                    .fold(*sample as f64, |sample, filter| filter.process(sample))
+                    as f32;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48_000;
+    const NYQUIST: f64 = SAMPLE_RATE as f64 / 2.0;
+
+    /// Gain of the band's filter at a frequency, in dB, measured with a settled cosine.
+    fn gain_at(band: &EqBand, frequency: f64) -> f64 {
+        let mut filter = band.biquad(SAMPLE_RATE);
+        let len = SAMPLE_RATE as usize;
+        let (mut input, mut output) = (0.0, 0.0);
+        for n in 0..len {
+            let x = (2.0 * PI * frequency * n as f64 / SAMPLE_RATE as f64).cos();
+            let y = filter.process(x);
+            if n >= len / 2 {
+                input += x * x;
+                output += y * y;
+            }
+        }
+
+        10.0 * (output / input).log10()
+    }
+
+    fn band(kind: EqBandKind, frequency: f32, gain: f32) -> EqBand {
+        EqBand {
+            kind,
+            frequency,
+            gain,
+            q: EqBand::DEFAULT_Q,
+        }
+    }
+
+    fn assert_gain(band: &EqBand, frequency: f64, expected: f64) {
+        let gain = gain_at(band, frequency);
+        assert!(
+            (gain - expected).abs() < 0.1,
+            "{band:?} at {frequency} Hz: {gain} dB, expected {expected} dB"
+        );
+    }
+
+    #[test]
+    fn peak_boosts_around_its_frequency() {
+        let peak = band(EqBandKind::Peak, 1_000.0, 6.0);
+        assert_gain(&peak, 1_000.0, 6.0);
+        assert_gain(&peak, 0.0, 0.0);
+        assert_gain(&peak, NYQUIST, 0.0);
+
+        let cut = band(EqBandKind::Peak, 1_000.0, -6.0);
+        assert_gain(&cut, 1_000.0, -6.0);
+    }
+
+    #[test]
+    fn shelves_boost_their_side() {
+        let low = band(EqBandKind::LowShelf, 200.0, 6.0);
+        assert_gain(&low, 0.0, 6.0);
+        assert_gain(&low, 200.0, 3.0);
+        assert_gain(&low, NYQUIST, 0.0);
+
+        let high = band(EqBandKind::HighShelf, 5_000.0, -6.0);
+        assert_gain(&high, 0.0, 0.0);
+        assert_gain(&high, 5_000.0, -3.0);
+        assert_gain(&high, NYQUIST, -6.0);
+    }
+
+    #[test]
+    fn passes_are_3db_down_at_their_corner() {
+        let low = band(EqBandKind::LowPass, 1_000.0, 0.0);
+        assert_gain(&low, 0.0, 0.0);
+        assert_gain(&low, 1_000.0, -3.01);
+        assert!(gain_at(&low, NYQUIST) < -100.0);
+
+        let high = band(EqBandKind::HighPass, 1_000.0, 0.0);
+        assert_gain(&high, NYQUIST, 0.0);
+        assert_gain(&high, 1_000.0, -3.01);
+        assert!(gain_at(&high, 0.0) < -100.0);
+    }
+}
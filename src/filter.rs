@@ -0,0 +1,103 @@
+//! ffmpeg filters applied to decoded audio.
+use ffmpeg_next as ffm;
+
+const SOURCE: &str = "in";
+const SINK: &str = "out";
+
+/// Runs packed `f32` audio through an ffmpeg filter graph,
+/// keeping its sample rate and channel layout.
+pub(crate) struct AudioFilter {
+    graph: ffm::filter::Graph,
+    layout: ffm::ChannelLayout,
+
+    /// Frames sent to the filter, used as the timestamp of the next frame.
+    pts: i64,
+}
+
+impl AudioFilter {
+    /// # Arguments
+    /// + `spec`: Filter graph description, e.g. `atempo=1.5`.
+    pub fn new(
+        spec: &str,
+        sample_rate: u32,
+        layout: ffm::ChannelLayout,
+    ) -> Result<Self, ffm::Error> {
+        let mut graph = ffm::filter::Graph::new();
+        let args = format!(
+            "time_base=1/{sample_rate}:sample_rate={sample_rate}:sample_fmt=flt:channel_layout=0x{:x}",
+            layout.bits()
+        );
+        graph.add(
+            &ffm::filter::find("abuffer").ok_or(ffm::Error::FilterNotFound)?,
+            SOURCE,
+            &args,
+        )?;
+        graph.add(
+            &ffm::filter::find("abuffersink").ok_or(ffm::Error::FilterNotFound)?,
+            SINK,
+            "",
+        )?;
+
+        // convert back to the format audio came in as
+        let spec = format!(
+            "{spec},aformat=sample_fmts=flt:sample_rates={sample_rate}:channel_layouts=0x{:x}",
+            layout.bits()
+        );
+        graph.output(SOURCE, 0)?.input(SINK, 0)?.parse(&spec)?;
+        graph.validate()?;
+
+        Ok(Self {
+            graph,
+            layout,
+            pts: 0,
+        })
+    }
+
+    /// Filters interleaved samples.
+    /// Filtered audio is appended to `out`, and may lag behind the input.
+    pub fn run(&mut self, samples: &[f32], out: &mut Vec<f32>) -> Result<(), ffm::Error> {
+        let channels = self.layout.channels().max(1) as usize;
+        let frames = samples.len() / channels;
+        if frames == 0 {
+            return Ok(());
+        }
+
+        let mut frame = ffm::frame::Audio::new(
+            ffm::format::Sample::F32(ffm::format::sample::Type::Packed),
+            frames,
+            self.layout,
+        );
+        frame.set_pts(Some(self.pts));
+        for (dst, src) in frame.data_mut(0).chunks_exact_mut(4).zip(samples) {
+            dst.copy_from_slice(&src.to_ne_bytes());
+        }
+        self.pts += frames as i64;
+
+        self.context(SOURCE).source().add(&frame)?;
+        self.receive(out)
+    }
+
+    /// Flushes audio held by the filter into `out`.
+    /// No more audio can be filtered afterwards.
+    pub fn drain(&mut self, out: &mut Vec<f32>) -> Result<(), ffm::Error> {
+        self.context(SOURCE).source().flush()?;
+        self.receive(out)
+    }
+
+    fn context(&mut self, name: &str) -> ffm::filter::Context {
+        self.graph.get(name).expect("filter is part of the graph")
+    }
+
+    fn receive(&mut self, out: &mut Vec<f32>) -> Result<(), ffm::Error> {
+        let mut filtered = ffm::frame::Audio::empty();
+        let mut sink = self.context(SINK);
+        loop {
+            match sink.sink().frame(&mut filtered) {
+                Ok(()) => out.extend_from_slice(crate::packed::<f32>(&filtered)),
+                Err(ffm::Error::Eof) => return Ok(()),
+                Err(ffm::Error::Other { errno }) if errno == ffm::error::EAGAIN => return Ok(()),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
@@ -1,10 +1,17 @@
-use crate::{CMD_KEY_RESTART, CMD_KEY_TOGGLE_AUTOPLAY, CMD_KEY_TOGGLE_SHOW_STATE};
+use crate::{
+    CMD_KEY_CYCLE_REPLAY_GAIN, CMD_KEY_DEVICE, CMD_KEY_EQ, CMD_KEY_FILTER, CMD_KEY_RESTART,
+    CMD_KEY_SEEK, CMD_KEY_SEEK_BACK, CMD_KEY_SEEK_FORWARD, CMD_KEY_STATS, CMD_KEY_TEMPO,
+    CMD_KEY_TEMPO_DOWN, CMD_KEY_TEMPO_UP, CMD_KEY_TOGGLE_AUTOPLAY, CMD_KEY_TOGGLE_CROSSFADE,
+    CMD_KEY_TOGGLE_EQ, CMD_KEY_TOGGLE_MUTE, CMD_KEY_TOGGLE_SHOW_STATE, CMD_KEY_VOLUME,
+    CMD_KEY_VOLUME_DOWN, CMD_KEY_VOLUME_UP, SEEK_STEP, TEMPO_STEP, VOLUME_STEP,
+};
 
 use super::{
     Command, CMD_KEY_NEXT, CMD_KEY_PREVIOUS, CMD_KEY_QUIT, CMD_KEY_TOGGLE_LOOP, CMD_KEY_TOGGLE_PLAY,
 };
 use crossbeam::channel;
-use std::io;
+use sensit_audio_cli as lib;
+use std::{io, time::Duration};
 
 pub struct InputActor {
     command_tx: channel::Sender<Command>,
@@ -37,15 +44,99 @@ impl InputActor {
 }
 
 fn command_from_str(input: impl AsRef<str>) -> Option<Command> {
-    match input.as_ref() {
+    let input = input.as_ref();
+    let (key, arg) = match input.split_once(char::is_whitespace) {
+        Some((key, arg)) => (key, arg.trim()),
+        None => (input, ""),
+    };
+
+    match key {
         CMD_KEY_QUIT => Some(Command::Quit),
         CMD_KEY_PREVIOUS => Some(Command::Previous),
         CMD_KEY_NEXT => Some(Command::Next),
         CMD_KEY_RESTART => Some(Command::Restart),
+        CMD_KEY_SEEK => seek_from_str(arg).map(Command::Seek),
+        CMD_KEY_SEEK_FORWARD => {
+            offset_from_str(arg).map(|offset| Command::Seek(lib::Seek::Forward(offset)))
+        }
+        CMD_KEY_SEEK_BACK => {
+            offset_from_str(arg).map(|offset| Command::Seek(lib::Seek::Backward(offset)))
+        }
         CMD_KEY_TOGGLE_PLAY => Some(Command::TogglePlay),
         CMD_KEY_TOGGLE_LOOP => Some(Command::ToggleLoop),
         CMD_KEY_TOGGLE_AUTOPLAY => Some(Command::ToggleAutoplay),
         CMD_KEY_TOGGLE_SHOW_STATE => Some(Command::ToggleShowState),
+        CMD_KEY_TOGGLE_CROSSFADE => Some(Command::ToggleCrossfade),
+        CMD_KEY_VOLUME_UP => Some(Command::ChangeVolume(VOLUME_STEP)),
+        CMD_KEY_VOLUME_DOWN => Some(Command::ChangeVolume(-VOLUME_STEP)),
+        CMD_KEY_VOLUME => volume_from_str(arg).map(Command::SetVolume),
+        CMD_KEY_TOGGLE_MUTE => Some(Command::ToggleMute),
+        CMD_KEY_CYCLE_REPLAY_GAIN => Some(Command::CycleReplayGain),
+        CMD_KEY_TEMPO_UP => Some(Command::ChangeTempo(TEMPO_STEP)),
+        CMD_KEY_TEMPO_DOWN => Some(Command::ChangeTempo(-TEMPO_STEP)),
+        CMD_KEY_TEMPO => tempo_from_str(arg).map(Command::SetTempo),
+        CMD_KEY_FILTER => Some(Command::SetFilter(
+            (!arg.is_empty()).then(|| arg.to_string()),
+        )),
+        CMD_KEY_EQ => Some(Command::SetEqPreset(
+            (!arg.is_empty()).then(|| arg.to_string()),
+        )),
+        CMD_KEY_TOGGLE_EQ => Some(Command::ToggleEq),
+        CMD_KEY_STATS => Some(Command::ShowStats),
+        CMD_KEY_DEVICE => Some(Command::SetDevice(
+            (!arg.is_empty()).then(|| arg.to_string()),
+        )),
         _ => None,
     }
 }
+
+/// Parses a seek of the form `[+|-]<time>`.
+/// A sign makes the seek relative to the current position.
+fn seek_from_str(input: &str) -> Option<lib::Seek> {
+    if let Some(offset) = input.strip_prefix('+') {
+        time_from_str(offset).map(lib::Seek::Forward)
+    } else if let Some(offset) = input.strip_prefix('-') {
+        time_from_str(offset).map(lib::Seek::Backward)
+    } else {
+        time_from_str(input).map(lib::Seek::To)
+    }
+}
+
+/// Parses a seek offset, using the default step if empty.
+fn offset_from_str(input: &str) -> Option<Duration> {
+    if input.is_empty() {
+        Some(SEEK_STEP)
+    } else {
+        time_from_str(input)
+    }
+}
+
+/// Parses a volume given as a percentage, e.g. `80` or `80%`.
+fn volume_from_str(input: &str) -> Option<f32> {
+    let percent = input.trim_end_matches('%').trim().parse::<f32>().ok()?;
+    if !(0.0..=100.0).contains(&percent) {
+        return None;
+    }
+
+    Some(percent / 100.0)
+}
+
+/// Parses a tempo given as a factor, e.g. `1.5` or `1.5x`.
+fn tempo_from_str(input: &str) -> Option<f32> {
+    let tempo = input.trim_end_matches('x').trim().parse::<f32>().ok()?;
+    (tempo > 0.0).then_some(tempo)
+}
+
+/// Parses a time of the form `[[hh:]mm:]ss[.fff]`.
+fn time_from_str(input: &str) -> Option<Duration> {
+    let mut secs = 0.0;
+    for part in input.split(':') {
+        let value = part.trim().parse::<f64>().ok()?;
+        if value.is_sign_negative() {
+            return None;
+        }
+        secs = secs * 60.0 + value;
+    }
+
+    Duration::try_from_secs_f64(secs).ok()
+}
@@ -1,9 +1,36 @@
+mod biquad;
+mod cache;
+mod control;
+mod crossfade;
+mod equalizer;
+mod filter;
+mod loudness;
+mod pool;
+mod replay_gain;
+mod stats;
+mod volume;
+
+pub use cache::{DecodedCache, DecodedTrack, Reservation};
+pub use control::StreamControl;
 use cpal::traits::*;
+use crossbeam::channel;
+pub use crossfade::{Crossfade, CrossfadeConfig, FadeCurve};
+pub use equalizer::{EqBand, EqBandKind};
 use ffmpeg_next as ffm;
+pub use loudness::{Loudness, LoudnessCache, LoudnessMeter, REPLAY_GAIN_REFERENCE};
+pub use pool::{Allocations, BufferPool, PoolStats};
+pub use replay_gain::{ReplayGainConfig, ReplayGainMode, ReplayGainTags};
 use ringbuf::traits::*;
+pub use stats::{PlaybackStats, Underrun};
 use std::{
+    cmp,
+    ops::RangeInclusive,
     path::PathBuf,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
 #[derive(derive_more::Debug)]
@@ -31,6 +58,22 @@ impl AudioFile {
     pub fn ctx_mut(&mut self) -> &mut ffm::format::context::Input {
         &mut self.ctx
     }
+
+    /// Sample rate of the file's audio, if it has an audio stream.
+    pub fn sample_rate(&self) -> Option<u32> {
+        self.source_format().ok().map(|format| format.sample_rate())
+    }
+
+    /// Format of the file's audio stream.
+    pub fn source_format(&self) -> Result<SourceFormat, ffm::Error> {
+        let stream = self
+            .ctx
+            .streams()
+            .best(ffm::media::Type::Audio)
+            .ok_or(ffm::Error::StreamNotFound)?;
+        let ctx = ffm::codec::Context::from_parameters(stream.parameters())?;
+        Ok(SourceFormat::of(&ctx.decoder().audio()?))
+    }
 }
 
 #[derive(Debug, derive_more::Deref, derive_more::DerefMut)]
@@ -77,6 +120,19 @@ impl PlaylistQueue {
         }
     }
 
+    /// The track [`Self::next`] would move to, without moving.
+    pub fn peek_next(&self) -> Option<&PathBuf> {
+        if self.cfg.loop_playlist {
+            if self.playlist.is_empty() {
+                None
+            } else {
+                self.playlist.get((self.index + 1) % self.playlist.len())
+            }
+        } else {
+            self.playlist.get(self.index + 1)
+        }
+    }
+
     pub fn next_back(&mut self) -> Option<&PathBuf> {
         if self.cfg.loop_playlist {
             if self.index == 0 {
@@ -139,290 +195,2169 @@ impl Default for AudioPlayConfig {
     }
 }
 
+/// Length of the fades around pausing, resuming, and discarding audio,
+/// unless set with [`AudioStreamBuilder::with_fade`].
+pub const DEFAULT_PLAYBACK_FADE: Duration = Duration::from_millis(10);
+
+/// Longest a full buffer is waited on without hearing from the device.
+const PUSH_WAKE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Number of errors reported by the output stream in a row,
+/// without the device asking for audio in between,
+/// after which the device is taken to be lost.
+const MAX_DEVICE_ERRORS: u32 = 16;
+
+/// Tempos a stream can be played at.
+pub const TEMPO_RANGE: RangeInclusive<f32> = 0.5..=2.0;
+
 type BufferProd<T> = ringbuf::CachingProd<Arc<ringbuf::HeapRb<T>>>;
+type BufferCons<T> = ringbuf::CachingCons<Arc<ringbuf::HeapRb<T>>>;
 pub struct AudioStreamBuilder {
     device: cpal::Device,
     stream_config: cpal::SupportedStreamConfig,
-    buffer_size: usize,
+
+    /// Length of audio buffered ahead of playback.
+    buffer: Duration,
+
+    /// Number of frames the device asks for at a time.
+    /// `None` leaves it to the device.
+    period: Option<u32>,
+
+    /// Layout to mix audio to before it is spread over the device's channels.
+    /// `None` mixes straight to the device's layout.
+    channel_layout: Option<ffm::ChannelLayout>,
+
+    /// Length of the fades around pausing, resuming, and discarding audio.
+    fade: Duration,
+    decode_error_policy: DecodeErrorPolicy,
+
+    /// Whether the device is opened in each track's own format, see [`Self::configure_for`].
+    bit_perfect: bool,
+
+    /// Memory reused by the outputs and streams built.
+    pool: BufferPool,
 }
 
 impl AudioStreamBuilder {
+    /// # Arguments
+    /// + `buffer`: Length of audio buffered ahead of playback.
+    ///     Longer buffers ride out slow decoding, but take longer to fill.
     pub fn new(
         device: cpal::Device,
         stream_config: cpal::SupportedStreamConfig,
-        buffer_size: usize,
+        buffer: Duration,
     ) -> Self {
         Self {
             device,
             stream_config,
-            buffer_size,
+            buffer,
+            period: None,
+            channel_layout: None,
+            fade: DEFAULT_PLAYBACK_FADE,
+            decode_error_policy: DecodeErrorPolicy::default(),
+            bit_perfect: false,
+            pool: BufferPool::new(),
         }
     }
 
-    /// Plays an audio file
+    /// Mix audio down (or up) to the given layout,
+    /// e.g. [`ChannelLayout::MONO`](ffm::ChannelLayout::MONO),
+    /// regardless of the layout of the file or device.
+    pub fn with_channel_layout(mut self, layout: ffm::ChannelLayout) -> Self {
+        let _ = self.channel_layout.insert(layout);
+        self
+    }
+
+    /// Fade audio out over the given time before pausing or discarding it,
+    /// and back in when resuming, rather than cutting it off with a click.
+    pub fn with_fade(mut self, fade: Duration) -> Self {
+        self.fade = fade;
+        self
+    }
+
+    /// How streams handle audio that can not be decoded.
+    pub fn with_decode_error_policy(mut self, policy: DecodeErrorPolicy) -> Self {
+        self.decode_error_policy = policy;
+        self
+    }
+
+    /// Ask the device for audio in blocks of the given number of frames,
+    /// rather than its default, e.g. for lower latency.
+    /// The output's buffer holds at least two blocks.
+    pub fn with_period_size(mut self, frames: u32) -> Self {
+        let _ = self.period.insert(frames);
+        self
+    }
+
+    /// Open the device in each track's own sample rate, channels, and sample format,
+    /// so its audio is played without being resampled or converted.
+    /// The builder is configured for each track with [`Self::configure_for`] before it is loaded.
+    pub fn with_bit_perfect(mut self, bit_perfect: bool) -> Self {
+        self.bit_perfect = bit_perfect;
+        self
+    }
+
+    pub fn is_bit_perfect(&self) -> bool {
+        self.bit_perfect
+    }
+
+    /// Reuse memory from the given pool, e.g. one shared with another builder.
+    pub fn with_buffer_pool(mut self, pool: BufferPool) -> Self {
+        self.pool = pool;
+        self
+    }
+
+    /// Pool of memory reused by the outputs and streams built.
+    pub fn buffer_pool(&self) -> &BufferPool {
+        &self.pool
+    }
+
+    pub fn device(&self) -> &cpal::Device {
+        &self.device
+    }
+
+    pub fn stream_config(&self) -> &cpal::SupportedStreamConfig {
+        &self.stream_config
+    }
+
+    /// Play through another device, e.g. after the last one was lost.
+    /// Only outputs built afterwards use it.
+    /// Streams are decoded to the device's format,
+    /// so streams loaded into an earlier output have to be loaded again.
+    pub fn set_device(&mut self, device: cpal::Device, stream_config: cpal::SupportedStreamConfig) {
+        self.device = device;
+        self.stream_config = stream_config;
+    }
+
+    /// Device config that plays a track bit-perfect,
+    /// at its own sample rate, channels, and sample format.
+    /// See [`AudioFile::source_format`] and [`AudioStream::source_format`].
     ///
-    /// # Returns
-    /// (play/pause control, stream)
+    /// Audio is still buffered as `f32`, which holds samples of up to 24 bits exactly.
+    /// Gain, filters, and the equalizer still change the audio if they are used.
     ///
-    /// # Panics
-    /// + If the player is not ready. (See [`Self::is_ready`].)
-    pub fn load(&self, mut audio_file: AudioFile) -> Result<AudioStream, ffm::Error> {
-        // NOTE: Could create buffer pool for reuse.
-        let (buffer_prod, mut buffer_cons) = ringbuf::HeapRb::new(self.buffer_size).split();
+    /// # Returns
+    /// + `Err` if the track can not be played bit-perfect on the device.
+    pub fn bit_perfect_config(
+        &self,
+        source: &SourceFormat,
+    ) -> Result<cpal::SupportedStreamConfig, error::BitPerfect> {
+        let sample_rate = source.sample_rate();
+        let channels = source.channels();
+        let sample_format = device_sample_format(source.sample_format())
+            .ok_or(error::BitPerfect::UnsupportedFormat(source.sample_format()))?;
+        if !converts_exactly(source) {
+            return Err(error::BitPerfect::SampleBits(source.bits_per_sample()));
+        }
 
-        audio_file.ctx_mut().seek(0, ..0).unwrap();
+        // the device's channels are taken to be in the default layout for their number
+        let device_layout = ffm::ChannelLayout::default(i32::from(channels));
+        let file_layout = source.channel_layout();
+        if !file_layout.is_empty() && file_layout != device_layout {
+            return Err(error::BitPerfect::FileLayout(file_layout));
+        }
 
-        // Find the audio stream and its index
-        let audio_stream = audio_file
-            .ctx()
-            .streams()
-            .best(ffm::media::Type::Audio)
-            .ok_or(ffm::Error::StreamNotFound)?;
+        if let Some(layout) = self.channel_layout {
+            if layout != device_layout {
+                return Err(error::BitPerfect::ChannelLayout(layout));
+            }
+        }
 
-        let audio_stream_index = audio_stream.index();
+        self.device
+            .supported_output_configs()
+            .map_err(error::BitPerfect::Device)?
+            .filter(|range| range.channels() == channels && range.sample_format() == sample_format)
+            .find_map(|range| range.try_with_sample_rate(cpal::SampleRate(sample_rate)))
+            .ok_or(error::BitPerfect::NoConfig {
+                sample_rate,
+                channels,
+                sample_format,
+            })
+    }
 
-        // Create a decoder
-        let ctx = ffm::codec::Context::from_parameters(audio_stream.parameters())?;
-        let audio_decoder = ctx.decoder().audio()?;
+    /// Opens the device in a track's format, see [`Self::bit_perfect_config`].
+    /// As with [`Self::set_device`], only outputs built afterwards use it.
+    ///
+    /// # Returns
+    /// Whether the config changed, so a new output has to be built for the track.
+    /// + `Err` if the track can not be played bit-perfect, leaving the config as it was.
+    pub fn configure_for(&mut self, source: &SourceFormat) -> Result<bool, error::BitPerfect> {
+        let config = self.bit_perfect_config(source)?;
+        if config == self.stream_config {
+            return Ok(false);
+        }
 
-        // Set up a resampler for the audio
-        let resampler = ffm::software::resampling::context::Context::get(
-            audio_decoder.format(),
-            audio_decoder.channel_layout(),
-            audio_decoder.rate(),
-            self.stream_config.sample_format().as_ffmpeg_sample(),
-            audio_decoder.channel_layout(),
-            self.stream_config.sample_rate().0,
-        )?;
+        self.stream_config = config;
+        Ok(true)
+    }
 
-        let audio_stream = match self.stream_config.sample_format() {
-            cpal::SampleFormat::F32 => {
-                self.device.build_output_stream(
-                    &self.stream_config.clone().into(),
-                    move |data: &mut [f32], cbinfo| {
-                        // Copy to the audio buffer (if there aren't enough samples, write_audio will write silence)
-                        write_audio(data, &mut buffer_cons, &cbinfo);
-                    },
-                    |err| eprintln!("error occurred on the audio output stream: {}", err),
-                    None,
-                )
+    /// Capacity of the output's buffer, in samples.
+    fn buffer_size(&self) -> usize {
+        let sample_rate = self.stream_config.sample_rate().0 as f64;
+        let frames = (self.buffer.as_secs_f64() * sample_rate).ceil() as usize;
+        let min_frames = self.period.map_or(1, |period| period as usize * 2);
+        frames.max(min_frames) * self.stream_config.channels() as usize
+    }
+
+    /// Config the device's stream is opened with.
+    fn output_config(&self) -> Result<cpal::StreamConfig, error::AudioOutput> {
+        let mut config = self.stream_config.config();
+        if let Some(period) = self.period {
+            if let cpal::SupportedBufferSize::Range { min, max } = *self.stream_config.buffer_size()
+            {
+                if !(min..=max).contains(&period) {
+                    return Err(error::AudioOutput::UnsupportedPeriodSize { period, min, max });
+                }
             }
-            cpal::SampleFormat::I16 => panic!("i16 output format unimplemented"),
-            cpal::SampleFormat::U16 => panic!("u16 output format unimplemented"),
-            _ => panic!("output format unimplemented"),
+
+            config.buffer_size = cpal::BufferSize::Fixed(period);
         }
-        .unwrap();
 
-        Ok(AudioStream {
-            audio_file,
-            audio_stream,
-            stream_index: audio_stream_index,
-            decoder: audio_decoder,
-            resampler,
+        Ok(config)
+    }
+
+    /// Layout of the device's channels.
+    fn device_layout(&self) -> ffm::ChannelLayout {
+        ffm::ChannelLayout::default(self.stream_config.channels() as i32)
+    }
+
+    /// Opens the output stream of the device.
+    /// The output is meant to live for the whole session,
+    /// with each track's [`AudioStream`] feeding its buffer in turn.
+    ///
+    /// Audio is buffered as `f32` and converted to the device's sample format
+    /// as it is played.
+    pub fn build_output(&self) -> Result<AudioOutput, error::AudioOutput> {
+        let config = self.output_config()?;
+        let buffer_size = self.buffer_size();
+
+        let (buffer_prod, buffer_cons) = self.pool.take_ring_buffer(buffer_size).split();
+
+        let sample_rate = self.stream_config.sample_rate().0;
+        let cursor = Arc::new(BufferCursor::default());
+        let gain = Arc::new(volume::Gain::new(1.0));
+        let fade = Arc::new(volume::Fade::new(self.fade, sample_rate));
+        let (wake_tx, wake_rx) = channel::bounded(1);
+        let underruns = Arc::new(stats::UnderrunCounter::new());
+        let device = Arc::new(DeviceStatus::default());
+        let callback = OutputCallback {
+            buffer_cons,
+            channels: self.stream_config.channels(),
+            cursor: cursor.clone(),
+            gain: volume::GainRamp::new(gain.clone(), sample_rate),
+            fade: volume::FadeRamp::new(fade.clone()),
+            wake: wake_tx.clone(),
+            underruns: underruns.clone(),
+            device: device.clone(),
+        };
+
+        let stream = match self.stream_config.sample_format() {
+            cpal::SampleFormat::I8 => self.build_stream::<i8>(&config, callback),
+            cpal::SampleFormat::I16 => self.build_stream::<i16>(&config, callback),
+            cpal::SampleFormat::I32 => self.build_stream::<i32>(&config, callback),
+            cpal::SampleFormat::I64 => self.build_stream::<i64>(&config, callback),
+            cpal::SampleFormat::U8 => self.build_stream::<u8>(&config, callback),
+            cpal::SampleFormat::U16 => self.build_stream::<u16>(&config, callback),
+            cpal::SampleFormat::U32 => self.build_stream::<u32>(&config, callback),
+            cpal::SampleFormat::U64 => self.build_stream::<u64>(&config, callback),
+            cpal::SampleFormat::F32 => self.build_stream::<f32>(&config, callback),
+            cpal::SampleFormat::F64 => self.build_stream::<f64>(&config, callback),
+            format => return Err(error::AudioOutput::UnsupportedFormat(format)),
+        }?;
+
+        Ok(AudioOutput {
+            stream,
             buffer_prod,
-            state: Arc::new(Mutex::new(StreamState::Pause)),
+            cursor,
+            gain,
+            volume: 1.0,
+            fade,
+            wake_tx,
+            wake_rx,
+            underruns,
+            underrun_log: stats::UnderrunLog::default(),
+            decode_meter: stats::DecodeMeter::default(),
+            device,
+            pool: self.pool.clone(),
+            buffer_size,
+            written: 0,
+            playing: false,
+            sample_rate,
+            channels: self.stream_config.channels(),
         })
     }
+
+    fn build_stream<T>(
+        &self,
+        config: &cpal::StreamConfig,
+        mut callback: OutputCallback,
+    ) -> Result<cpal::Stream, cpal::BuildStreamError>
+    where
+        T: cpal::SizedSample + cpal::FromSample<f32>,
+    {
+        let device = callback.device.clone();
+        let wake = callback.wake.clone();
+        self.device.build_output_stream(
+            config,
+            move |data: &mut [T], cbinfo| {
+                // the device is still there
+                callback.device.errors.store(0, Ordering::Relaxed);
+
+                // Copy to the audio buffer (if there aren't enough samples, write_audio will write silence)
+                let consumed = callback.cursor.consumed.load(Ordering::Relaxed);
+                let held = callback.fade.is_held();
+                write_audio(data, &mut callback, cbinfo);
+                if callback.cursor.consumed.load(Ordering::Relaxed) != consumed
+                    || callback.fade.is_held() != held
+                {
+                    // let the stream feeding the buffer fill it back up,
+                    // or the output pause once faded out
+                    let _ = callback.wake.try_send(());
+                }
+            },
+            move |err| {
+                let lost = match err {
+                    cpal::StreamError::DeviceNotAvailable => true,
+                    cpal::StreamError::BackendSpecific { err } => {
+                        // some backends keep reporting errors for a device that is gone
+                        let errors = device.errors.fetch_add(1, Ordering::Relaxed) + 1;
+                        if errors == 1 {
+                            tracing::warn!(%err, "error on the audio output stream");
+                        }
+                        errors >= MAX_DEVICE_ERRORS
+                    }
+                };
+
+                if lost && !device.lost.swap(true, Ordering::AcqRel) {
+                    // let the thread feeding the output notice
+                    let _ = wake.try_send(());
+                }
+            },
+            None,
+        )
+    }
+
+    /// Format audio is decoded to for the output.
+    pub fn decode_format(&self) -> DecodeFormat {
+        let device_layout = self.device_layout();
+        DecodeFormat {
+            sample_rate: self.stream_config.sample_rate().0,
+            mix_layout: self.channel_layout.unwrap_or(device_layout),
+            device_layout,
+            bit_perfect: self.bit_perfect,
+        }
+    }
+
+    pub fn decode_error_policy(&self) -> DecodeErrorPolicy {
+        self.decode_error_policy
+    }
+
+    /// Prepares an audio file to be played through the output.
+    pub fn load(
+        &self,
+        audio_file: AudioFile,
+        output: &AudioOutput,
+    ) -> Result<AudioStream, ffm::Error> {
+        let source = FileSource::open(
+            audio_file,
+            self.decode_format(),
+            self.decode_error_policy,
+            &self.pool,
+        )?;
+        Ok(self.stream(Source::File(source), output))
+    }
+
+    /// Prepares audio decoded earlier, e.g. from a [`DecodedCache`], to be played through the output.
+    ///
+    /// # Returns
+    /// `None` if the audio was decoded to a different format than the output's.
+    pub fn load_decoded(
+        &self,
+        track: Arc<DecodedTrack>,
+        output: &AudioOutput,
+    ) -> Option<AudioStream> {
+        if track.format() != self.decode_format() {
+            return None;
+        }
+
+        Some(self.stream(Source::Decoded { track, position: 0 }, output))
+    }
+
+    fn stream(&self, source: Source, output: &AudioOutput) -> AudioStream {
+        let clock = Arc::new(StreamClock::new(
+            output.cursor.clone(),
+            output.sample_rate() as u64 * output.channels() as u64,
+            source.duration(),
+        ));
+
+        AudioStream {
+            replay_gain: source.replay_gain(),
+            source,
+            format: self.decode_format(),
+            gain: 1.0,
+            applied_gain: 1.0,
+            tempo: 1.0,
+            filter_spec: None,
+            filter: None,
+            eq_bands: None,
+            equalizer: None,
+            clock,
+            started: false,
+            samples: self.pool.take_samples(),
+            decoded: self.pool.take_samples(),
+            ended: false,
+            pending: self.pool.take_samples(),
+            recording: None,
+            control: StreamControl::new(output.wake_tx.clone()),
+            pool: self.pool.clone(),
+        }
+    }
 }
 
+/// Output stream of the audio device.
+/// Tracks are played by loading their [`AudioStream`] into the output's buffer.
+///
 /// # Notes
 /// + !Send
-pub struct AudioStream {
-    audio_file: AudioFile,
-    audio_stream: cpal::Stream,
-    stream_index: usize,
-    decoder: ffm::decoder::Audio,
-    resampler: ffm::software::resampling::context::Context,
+pub struct AudioOutput {
+    stream: cpal::Stream,
     buffer_prod: BufferProd<f32>,
-    state: StreamStateLock,
+    cursor: Arc<BufferCursor>,
+    gain: Arc<volume::Gain>,
+    volume: f32,
+    fade: Arc<volume::Fade>,
+
+    /// Signalled as the device plays audio, freeing up buffer space,
+    /// and as the controls of streams loaded into the output change.
+    wake_tx: channel::Sender<()>,
+    wake_rx: channel::Receiver<()>,
+    underruns: Arc<stats::UnderrunCounter>,
+    underrun_log: stats::UnderrunLog,
+    decode_meter: stats::DecodeMeter,
+    device: Arc<DeviceStatus>,
+
+    /// Takes back the ring buffer once the output is dropped.
+    pool: BufferPool,
+
+    /// Capacity of the ring buffer, in samples.
+    buffer_size: usize,
+
+    /// Number of samples pushed to the ring buffer.
+    written: u64,
+    playing: bool,
+    sample_rate: u32,
+    channels: u16,
 }
 
-impl AudioStream {
-    pub fn state(&self) -> Arc<Mutex<StreamState>> {
-        self.state.clone()
-    }
-
-    pub fn load(&mut self) -> Result<(), error::AudioStream> {
-        let mut receive_and_queue_audio_frames =
-            |decoder: &mut ffm::decoder::Audio| -> Result<(), error::AudioStream> {
-                let mut decoded = ffm::frame::Audio::empty();
-
-                // Ask the decoder for frames
-                while decoder.receive_frame(&mut decoded).is_ok() {
-                    // Resample the frame's audio into another frame
-                    let mut resampled = ffm::frame::Audio::empty();
-                    self.resampler
-                        .run(&decoded, &mut resampled)
-                        .map_err(|err| error::AudioStream::Resample(err))?;
-
-                    // DON'T just use resampled.data(0).len() -- it might not be fully populated
-                    // Grab the right number of bytes based on sample count, bytes per sample, and number of channels.
-                    let both_channels = packed(&resampled);
-
-                    // Sleep until the buffer has enough space for all of the samples
-                    // (the producer will happily accept a partial write, which we don't want)
-                    while self.buffer_prod.vacant_len() < both_channels.len() {
-                        std::thread::sleep(std::time::Duration::from_millis(10));
-                    }
+impl AudioOutput {
+    /// Resumes playback, fading in.
+    pub fn play(&mut self) -> Result<(), error::AudioStream> {
+        if !self.playing {
+            self.fade.set_paused(false);
+            self.stream.play()?;
+            self.playing = true;
+        }
 
-                    // Buffer the samples for playback
-                    self.buffer_prod.push_slice(both_channels);
-                }
-                Ok(())
-            };
+        Ok(())
+    }
 
-        // Start playing
-        self.audio_stream.play()?;
-        for (stream, packet) in self.audio_file.ctx_mut().packets() {
-            let state = self.state.lock().unwrap();
-            if state.is_paused() {
-                drop(state);
-                self.audio_stream.pause()?;
-                loop {
-                    std::thread::sleep(std::time::Duration::from_millis(50));
-                    let state = self.state.lock().unwrap();
-                    if state.is_playing() {
-                        self.audio_stream.play()?;
-                        break;
-                    } else if state.is_stopped() {
-                        return Ok(());
-                    }
-                }
-            } else if state.is_stopped() {
-                return Ok(());
+    /// Pauses playback, once the audio has faded out.
+    /// Audio still queued is played when playback resumes.
+    pub fn pause(&mut self) -> Result<(), error::AudioStream> {
+        if self.playing {
+            self.fade.set_paused(true);
+            let deadline =
+                Instant::now() + self.fade.duration(self.sample_rate) + PUSH_WAKE_TIMEOUT;
+            while !self.fade.is_silent() && Instant::now() < deadline {
+                let _ = self.wake_rx.recv_deadline(deadline);
             }
 
-            // Look for audio packets (ignore video and others)
-            if stream.index() == self.stream_index {
-                // Send the packet to the decoder; it will combine them into frames.
-                // In practice though, 1 packet = 1 frame
-                self.decoder
-                    .send_packet(&packet)
-                    .map_err(|err| error::AudioStream::Decode(err))?;
-
-                // Queue the audio for playback (and block if the queue is full)
-                receive_and_queue_audio_frames(&mut self.decoder)?;
-            }
+            self.stream.pause()?;
+            self.playing = false;
         }
 
-        *self.state.lock().unwrap() = StreamState::Done;
         Ok(())
     }
-}
 
-#[derive(Copy, Clone, Debug)]
-pub enum StreamState {
-    Play,
-    Pause,
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
 
-    /// Stream stopped before end.
-    Stop,
+    /// The device went away, e.g. it was unplugged.
+    /// The output can no longer play, so a new one has to be built to carry on.
+    pub fn is_device_lost(&self) -> bool {
+        self.device.lost.load(Ordering::Acquire)
+    }
 
-    /// Stream played all the way through.
-    Done,
-}
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
 
-impl StreamState {
-    pub fn is_paused(&self) -> bool {
-        matches!(self, Self::Pause)
+    pub fn channels(&self) -> u16 {
+        self.channels
     }
 
-    pub fn is_playing(&self) -> bool {
-        matches!(self, Self::Play)
+    /// Volume, from `0` (silent) to `1` (full).
+    pub fn volume(&self) -> f32 {
+        self.volume
     }
 
-    pub fn is_stopped(&self) -> bool {
-        matches!(self, Self::Stop)
+    /// Sets the volume of the output.
+    /// Takes effect immediately, including for audio already queued,
+    /// ramping to the new volume over a few milliseconds.
+    ///
+    /// # Arguments
+    /// + `volume`: From `0` (silent) to `1` (full). Clamped to this range.
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume.clamp(0.0, 1.0);
+        self.gain.set(volume::volume_to_gain(self.volume));
     }
 
-    pub fn is_done(&self) -> bool {
-        matches!(self, Self::Done)
+    /// Checks an ffmpeg filter graph description can be applied to audio played to the output.
+    /// See [`AudioStream::set_filter`].
+    pub fn check_filter(&self, spec: &str) -> Result<(), error::AudioStream> {
+        let layout = ffm::ChannelLayout::default(self.channels as i32);
+        filter::AudioFilter::new(spec, self.sample_rate, layout)
+            .map(|_| ())
+            .map_err(error::AudioStream::Filter)
     }
-}
 
-pub type StreamStateLock = Arc<Mutex<StreamState>>;
+    /// Signalled when there may be something for the thread feeding the output to do:
+    /// buffer space freed up, or the [`StreamControl`] of a stream loaded into the output changed.
+    /// The thread can wait on this rather than polling.
+    pub fn wake(&self) -> &channel::Receiver<()> {
+        &self.wake_rx
+    }
 
-trait SampleFormatConversion {
-    fn as_ffmpeg_sample(&self) -> ffm::format::Sample;
-}
+    /// Number of samples queued to the output since it was built.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
 
-impl SampleFormatConversion for cpal::SampleFormat {
-    fn as_ffmpeg_sample(&self) -> ffm::format::Sample {
-        use ffm::format::sample::Type;
+    /// Number of samples the device has taken from the output since it was built,
+    /// including those discarded.
+    pub fn played(&self) -> u64 {
+        self.cursor.consumed.load(Ordering::Acquire)
+    }
 
-        match self {
-            Self::I16 => ffm::format::Sample::I16(Type::Packed),
-            Self::F32 => ffm::format::Sample::F32(Type::Packed),
-            Self::U16 => {
-                panic!("ffmpeg resampler doesn't support u16")
-            }
-            _ => panic!("ffmpeg resampler can not convert type"),
+    /// Length of the fades around pausing, resuming, and discarding audio.
+    pub fn fade(&self) -> Duration {
+        self.fade.duration(self.sample_rate)
+    }
+
+    pub fn set_fade(&mut self, fade: Duration) {
+        self.fade.set_duration(fade, self.sample_rate);
+    }
+
+    /// Health of the output's playback.
+    pub fn stats(&self) -> PlaybackStats {
+        let samples_to_duration = |samples: u64| {
+            stats::frames_to_duration(samples / self.channels.max(1) as u64, self.sample_rate)
+        };
+
+        PlaybackStats {
+            uptime: self.underruns.uptime(),
+            underruns: self.underruns.underruns(),
+            underrun_silence: stats::frames_to_duration(self.underruns.frames(), self.sample_rate),
+            recent_underruns: self.underrun_log.recent(),
+            buffered: samples_to_duration(self.written.saturating_sub(self.played())),
+            buffer_capacity: samples_to_duration(self.buffer_size as u64),
+            decode_speed: self.decode_meter.speed(),
+            recent_decode_speed: self.decode_meter.recent_speed(),
+            allocations: self.pool.stats(),
         }
     }
-}
 
-// Interpret the audio frame's data as packed (alternating channels, 12121212, as opposed to planar 11112222)
-pub fn packed<T: ffm::frame::audio::Sample>(frame: &ffm::frame::Audio) -> &[T] {
-    if !frame.is_packed() {
-        panic!("data is not packed");
+    /// Reports buffer underruns since the last check, if any, as a tracing event.
+    /// The thread feeding the output should check regularly,
+    /// so underruns are reported close to when they happen.
+    pub fn check_underruns(&mut self) -> Option<Underrun> {
+        let underrun = self
+            .underrun_log
+            .report(&self.underruns, self.sample_rate)?;
+
+        tracing::warn!(
+            time = ?underrun.time,
+            count = underrun.count,
+            silence = ?underrun.silence,
+            decode_speed = ?self.decode_meter.recent_speed(),
+            "buffer underrun, the decoder fell behind playback"
+        );
+        Some(underrun)
     }
 
-    if !<T as ffm::frame::audio::Sample>::is_valid(frame.format(), frame.channels()) {
-        panic!("unsupported type");
+    /// Discards all audio queued for playback.
+    /// Audio playing fades out first.
+    pub fn flush(&mut self) {
+        self.underruns.set_expecting(false);
+        self.cursor
+            .discard_until
+            .store(self.written, Ordering::Release);
     }
 
-    unsafe {
-        std::slice::from_raw_parts(
-            (*frame.as_ptr()).data[0] as *const T,
-            frame.samples() * frame.channels() as usize,
-        )
+    /// Queues interleaved samples for playback.
+    /// Blocks until all samples fit in the buffer,
+    /// the stream they belong to is interrupted,
+    /// or the device is lost.
+    ///
+    /// # Returns
+    /// Number of samples queued.
+    pub fn push(&mut self, samples: &[f32], control: &StreamControl) -> usize {
+        if !samples.is_empty() {
+            self.underruns.set_expecting(true);
+        }
+
+        let mut pushed = 0;
+        loop {
+            let count = self.buffer_prod.push_slice(&samples[pushed..]);
+            self.written += count as u64;
+            pushed += count;
+            if pushed == samples.len() || control.is_interrupted() || self.is_device_lost() {
+                return pushed;
+            }
+
+            // timeout in case the device stops playing without an error
+            let _ = self.wake_rx.recv_timeout(PUSH_WAKE_TIMEOUT);
+        }
     }
 }
 
-fn write_audio<T: cpal::Sample>(
-    data: &mut [T],
-    samples: &mut impl ringbuf::consumer::Consumer<Item = T>,
-    _: &cpal::OutputCallbackInfo,
-) {
-    for d in data {
-        // copy as many samples as we have.
-        // if we run out, write silence
-        match samples.try_pop() {
-            Some(sample) => *d = sample,
-            None => *d = cpal::Sample::EQUILIBRIUM,
-        }
+impl Drop for AudioOutput {
+    fn drop(&mut self) {
+        // reused by the next output once the stream drops the other half
+        let rb = ringbuf::wrap::Wrap::rb_ref(&self.buffer_prod).clone();
+        self.pool.put_ring_buffer(rb);
     }
 }
 
-pub mod error {
-    use ffmpeg_next as ffm;
+/// Decodes a track into an [`AudioOutput`].
+pub struct AudioStream {
+    source: Source,
 
-    #[derive(Debug)]
-    pub struct InvalidIndex;
+    /// Format the source decodes audio to.
+    format: DecodeFormat,
+    replay_gain: ReplayGainTags,
 
-    #[derive(Debug)]
-    pub enum AudioStream {
-        Resample(ffm::Error),
-        Decode(ffm::util::error::Error),
-        DeviceNotAvailable,
-        Other(String),
+    /// Gain to decode audio at.
+    gain: f32,
+
+    /// Gain the last decoded audio was at.
+    applied_gain: f32,
+
+    /// Speed the track is played at, without changing its pitch.
+    tempo: f32,
+
+    /// User supplied ffmpeg filter graph description.
+    filter_spec: Option<String>,
+
+    /// Runs audio through the user's filters,
+    /// then time stretches it to the tempo.
+    /// `None` when there are no filters and playing at normal speed.
+    filter: Option<filter::AudioFilter>,
+
+    /// Bands of the equalizer, `None` when bypassed.
+    eq_bands: Option<Vec<EqBand>>,
+    equalizer: Option<equalizer::Equalizer>,
+    clock: Arc<StreamClock>,
+
+    /// Whether any audio has been queued to the output yet.
+    started: bool,
+
+    /// Scratch buffer for processed samples.
+    samples: Vec<f32>,
+
+    /// Scratch buffer for samples from the source.
+    decoded: Vec<f32>,
+
+    /// The end of the track was reached and the source drained.
+    ended: bool,
+
+    /// Samples that did not fit in the output before the stream was interrupted.
+    /// Queued before any more audio is decoded.
+    pending: Vec<f32>,
+
+    /// Audio decoded from the track's file so far, to be cached once complete,
+    /// with the memory reserved for it in the cache.
+    /// `None` if not recording, or the recording was dropped.
+    recording: Option<(Vec<f32>, Reservation)>,
+    control: StreamControl,
+
+    /// Takes back the scratch buffers once the stream is dropped.
+    pool: BufferPool,
+}
+
+impl AudioStream {
+    /// Handle to play, pause, stop, and seek the stream from another thread.
+    pub fn control(&self) -> StreamControl {
+        self.control.clone()
     }
 
-    impl From<cpal::PlayStreamError> for AudioStream {
-        fn from(value: cpal::PlayStreamError) -> Self {
-            match value {
-                cpal::PlayStreamError::DeviceNotAvailable => Self::DeviceNotAvailable,
-                cpal::PlayStreamError::BackendSpecific { err } => Self::Other(err.description),
-            }
-        }
+    pub fn state(&self) -> StreamState {
+        self.control.state()
     }
 
-    impl From<cpal::PauseStreamError> for AudioStream {
-        fn from(value: cpal::PauseStreamError) -> Self {
-            match value {
-                cpal::PauseStreamError::DeviceNotAvailable => Self::DeviceNotAvailable,
-                cpal::PauseStreamError::BackendSpecific { err } => Self::Other(err.description),
-            }
-        }
+    pub fn set_state(&self, state: StreamState) {
+        self.control.set_state(state);
+    }
+
+    /// Handle to observe the playback position from another thread.
+    pub fn clock(&self) -> Arc<StreamClock> {
+        self.clock.clone()
+    }
+
+    /// File of the track.
+    pub fn path(&self) -> &PathBuf {
+        self.source.path()
+    }
+
+    /// The stream plays audio decoded earlier, rather than decoding its file.
+    pub fn is_decoded(&self) -> bool {
+        matches!(self.source, Source::Decoded { .. })
+    }
+
+    /// See [`StreamClock::position`].
+    pub fn position(&self) -> Duration {
+        self.clock.position()
+    }
+
+    /// See [`StreamClock::duration`].
+    pub fn duration(&self) -> Option<Duration> {
+        self.clock.duration()
+    }
+
+    /// Format of the audio in the track's file, before it is decoded to the output's format.
+    pub fn source_format(&self) -> SourceFormat {
+        self.source.source_format()
+    }
+
+    /// See [`StreamClock::remaining`].
+    pub fn remaining(&self) -> Option<Duration> {
+        self.clock.remaining()
+    }
+
+    /// ReplayGain values of the track.
+    pub fn replay_gain(&self) -> &ReplayGainTags {
+        &self.replay_gain
+    }
+
+    /// Uses the given values in place of the track's tags,
+    /// e.g. from an analysis of its loudness.
+    /// Apply them with [`Self::set_replay_gain`].
+    pub fn set_replay_gain_tags(&mut self, tags: ReplayGainTags) {
+        self.replay_gain = tags;
+    }
+
+    /// Sets the gain of the track from its ReplayGain values.
+    /// Audio already queued to the output is unaffected.
+    pub fn set_replay_gain(&mut self, config: &ReplayGainConfig) {
+        self.gain = self.replay_gain.gain(config);
+    }
+
+    pub fn tempo(&self) -> f32 {
+        self.tempo
+    }
+
+    /// Sets the speed the track is played at, without changing its pitch.
+    /// Audio already queued to the output keeps playing at the previous tempo.
+    ///
+    /// # Arguments
+    /// + `tempo`: Clamped to [`TEMPO_RANGE`].
+    pub fn set_tempo(
+        &mut self,
+        tempo: f32,
+        output: &mut AudioOutput,
+    ) -> Result<(), error::AudioStream> {
+        let tempo = tempo.clamp(*TEMPO_RANGE.start(), *TEMPO_RANGE.end());
+        if tempo == self.tempo {
+            return Ok(());
+        }
+
+        let filter = self.build_filter(self.filter_spec.as_deref(), tempo)?;
+        self.replace_filter(filter, output)?;
+        self.tempo = tempo;
+        self.clock
+            .set_tempo(output.written + self.pending.len() as u64, tempo);
+        Ok(())
+    }
+
+    /// User supplied ffmpeg filter graph description.
+    pub fn filter(&self) -> Option<&str> {
+        self.filter_spec.as_deref()
+    }
+
+    /// Runs the track's audio through an ffmpeg filter graph, e.g. `highpass=f=200,acompressor`.
+    /// Audio already queued to the output is unaffected.
+    /// If the filter can not be built, the current filter is kept.
+    ///
+    /// # Arguments
+    /// + `spec`: Filter graph description, or `None` to remove the filter.
+    ///     Filters should not change the length of the audio, as it would throw off the position.
+    pub fn set_filter(
+        &mut self,
+        spec: Option<String>,
+        output: &mut AudioOutput,
+    ) -> Result<(), error::AudioStream> {
+        let filter = self.build_filter(spec.as_deref(), self.tempo)?;
+        self.replace_filter(filter, output)?;
+        self.filter_spec = spec;
+        Ok(())
+    }
+
+    /// Bands of the equalizer, `None` if it is bypassed.
+    pub fn equalizer(&self) -> Option<&[EqBand]> {
+        self.eq_bands.as_deref()
+    }
+
+    /// Runs the track's audio through a parametric equalizer.
+    /// Audio already queued to the output is unaffected.
+    ///
+    /// # Arguments
+    /// + `bands`: Bands of the equalizer, or `None` to bypass it.
+    pub fn set_equalizer(&mut self, bands: Option<&[EqBand]>) {
+        self.eq_bands = bands.map(<[EqBand]>::to_vec);
+        self.equalizer = self.build_equalizer();
+    }
+
+    /// Keeps the audio decoded from the track's file,
+    /// so it can be cached once all of it has been decoded, see [`Self::take_decoded`].
+    /// Has no effect once the stream has started, or if it plays audio decoded earlier.
+    ///
+    /// # Arguments
+    /// + `reservation`: Memory set aside in the cache for the track, see [`Self::decoded_size`].
+    ///     The recording is dropped if the track is larger.
+    pub fn record_decoded(&mut self, reservation: Reservation) {
+        if self.started || self.is_decoded() {
+            return;
+        }
+
+        let samples = Vec::with_capacity(reservation.size() / std::mem::size_of::<f32>());
+        let _ = self.recording.insert((samples, reservation));
+    }
+
+    /// Bytes the audio decoded from the track's file takes, from the track's duration.
+    /// Allows an extra second for decoders that pad the end of the track.
+    /// `None` if the duration is not known.
+    pub fn decoded_size(&self) -> Option<usize> {
+        let duration = self.duration()? + Duration::from_secs(1);
+        let frames = (duration.as_secs_f64() * self.format.sample_rate() as f64).ceil() as usize;
+        Some(frames * self.format.channels() as usize * std::mem::size_of::<f32>())
+    }
+
+    /// Takes the audio recorded from the track's file,
+    /// if all of it was decoded, see [`Self::record_decoded`].
+    pub fn take_decoded(&mut self) -> Option<DecodedTrack> {
+        if !self.ended {
+            return None;
+        }
+
+        // the reservation is released for the track to take its place
+        let (samples, _) = self.recording.take()?;
+        Some(DecodedTrack::new(
+            self.path().clone(),
+            self.format,
+            self.source_format(),
+            samples,
+            self.replay_gain,
+            self.duration(),
+        ))
+    }
+
+    /// Decodes the next audio packet of the track and queues it to the output.
+    /// Blocks while the output buffer is full, until the stream is interrupted
+    /// through its [`StreamControl`].
+    /// Audio that did not fit is queued on the next call, before decoding more.
+    ///
+    /// # Arguments
+    /// + `fade`: Crossfade from the previous track to mix the audio with.
+    ///
+    /// # Returns
+    /// `false` once the end of the track is reached.
+    pub fn load_packet(
+        &mut self,
+        output: &mut AudioOutput,
+        fade: Option<&mut Crossfade>,
+    ) -> Result<bool, error::AudioStream> {
+        if !self.pending.is_empty() {
+            let mut pending = std::mem::take(&mut self.pending);
+            let pushed = output.push(&pending, &self.control);
+            pending.drain(..pushed);
+            self.pending = pending;
+            if !self.pending.is_empty() {
+                return Ok(true);
+            }
+        }
+
+        let mut samples = std::mem::take(&mut self.samples);
+        samples.clear();
+        let decode_start = Instant::now();
+        let loaded = self.decode_packet(&mut samples);
+        output.decode_meter.record(
+            stats::frames_to_duration(
+                (samples.len() / output.channels().max(1) as usize) as u64,
+                output.sample_rate(),
+            ),
+            decode_start.elapsed(),
+        );
+        if !samples.is_empty() {
+            if !self.started {
+                // track begins after the audio already queued
+                self.clock.set_mark(output.written, Duration::ZERO);
+                self.started = true;
+            }
+
+            if let Some(fade) = fade {
+                fade.mix(&mut samples, output.channels());
+            }
+
+            // Buffer the samples for playback (and block if the queue is full)
+            self.queue(&samples, output);
+        }
+
+        self.samples = samples;
+        match loaded {
+            // finish queuing the end of the track before reporting it
+            Ok(false) if !self.pending.is_empty() => Ok(true),
+            Ok(false) => {
+                // running dry from here on is the end of the audio, not an underrun
+                output.underruns.set_expecting(false);
+                Ok(false)
+            }
+            loaded => loaded,
+        }
+    }
+
+    /// Moves playback to a new position in the track.
+    /// Audio already queued to the output is discarded.
+    pub fn seek(&mut self, seek: Seek, output: &mut AudioOutput) -> Result<(), error::AudioStream> {
+        let target = match seek {
+            Seek::To(position) => position,
+            Seek::Forward(offset) => self.position().saturating_add(offset),
+            Seek::Backward(offset) => self.position().saturating_sub(offset),
+        };
+        let target = match self.duration() {
+            Some(duration) => target.min(duration),
+            None => target,
+        };
+
+        self.source.seek(target)?;
+        self.filter = self.build_filter(self.filter_spec.as_deref(), self.tempo)?;
+        self.equalizer = self.build_equalizer();
+        self.pending.clear();
+        self.ended = false;
+
+        // the recording would have a gap
+        self.recording = None;
+        output.flush();
+        self.clock.set_mark(output.written, target);
+        self.started = true;
+        tracing::debug!("seek to {target:?}");
+        Ok(())
+    }
+
+    /// Applies a seek requested through the stream's [`StreamControl`], if any.
+    pub fn apply_pending_seek(
+        &mut self,
+        output: &mut AudioOutput,
+    ) -> Result<(), error::AudioStream> {
+        if let Some(seek) = self.control.take_seek() {
+            self.seek(seek, output)?;
+        }
+
+        Ok(())
+    }
+
+    /// Time left to decode in the track at its tempo, if its length is known.
+    pub fn decode_remaining(&self) -> Option<Duration> {
+        self.duration().map(|duration| {
+            duration
+                .saturating_sub(self.source.position())
+                .div_f32(self.tempo)
+        })
+    }
+
+    /// Filter running audio through the user's filters, then stretching it to the tempo.
+    ///
+    /// # Returns
+    /// `None` if there are no filters and the tempo is normal speed.
+    fn build_filter(
+        &self,
+        spec: Option<&str>,
+        tempo: f32,
+    ) -> Result<Option<filter::AudioFilter>, error::AudioStream> {
+        let tempo = (tempo != 1.0).then(|| format!("atempo={tempo}"));
+        let spec = spec
+            .map(str::to_string)
+            .into_iter()
+            .chain(tempo)
+            .collect::<Vec<_>>()
+            .join(",");
+
+        if spec.is_empty() {
+            return Ok(None);
+        }
+
+        filter::AudioFilter::new(&spec, self.format.sample_rate, self.format.device_layout)
+            .map(Some)
+            .map_err(error::AudioStream::Filter)
+    }
+
+    fn build_equalizer(&self) -> Option<equalizer::Equalizer> {
+        self.eq_bands.as_ref().map(|bands| {
+            equalizer::Equalizer::new(bands, self.format.sample_rate, self.format.channels())
+        })
+    }
+
+    /// Equalizes filtered samples and brings them to the track's gain.
+    fn finish_samples(&mut self, samples: &mut [f32], channels: u16) {
+        if let Some(equalizer) = self.equalizer.as_mut() {
+            equalizer.process(samples);
+        }
+
+        volume::apply_gain(samples, channels, self.applied_gain, self.gain);
+        self.applied_gain = self.gain;
+    }
+
+    /// Queues samples to the output after any already pending,
+    /// keeping those that do not fit before the stream is interrupted.
+    fn queue(&mut self, samples: &[f32], output: &mut AudioOutput) {
+        let pushed = if self.pending.is_empty() {
+            output.push(samples, &self.control)
+        } else {
+            0
+        };
+
+        self.pending.extend_from_slice(&samples[pushed..]);
+    }
+
+    /// Swaps in a new filter, first playing out the audio held by the current one.
+    fn replace_filter(
+        &mut self,
+        filter: Option<filter::AudioFilter>,
+        output: &mut AudioOutput,
+    ) -> Result<(), error::AudioStream> {
+        if let Some(mut current) = std::mem::replace(&mut self.filter, filter) {
+            let mut samples = std::mem::take(&mut self.samples);
+            samples.clear();
+            current
+                .drain(&mut samples)
+                .map_err(error::AudioStream::Filter)?;
+
+            self.finish_samples(&mut samples, output.channels());
+            self.queue(&samples, output);
+            self.samples = samples;
+        }
+
+        Ok(())
+    }
+
+    /// Decodes the next audio packet of the track,
+    /// then filters, equalizes, and brings it to the track's gain.
+    ///
+    /// # Arguments
+    /// + `samples`: Buffer the decoded samples are appended to.
+    ///
+    /// # Returns
+    /// `false` once the end of the track is reached.
+    fn decode_packet(&mut self, samples: &mut Vec<f32>) -> Result<bool, error::AudioStream> {
+        if self.ended {
+            return Ok(false);
+        }
+
+        let mut decoded = std::mem::take(&mut self.decoded);
+        decoded.clear();
+        let more = self.source.read(&mut decoded)?;
+        if let Some((recording, _)) = self.recording.as_mut() {
+            if recording.len() + decoded.len() > recording.capacity() {
+                // too large to cache
+                self.recording = None;
+            } else {
+                recording.extend_from_slice(&decoded);
+            }
+        }
+
+        let start = samples.len();
+        match self.filter.as_mut() {
+            Some(filter) => {
+                filter
+                    .run(&decoded, samples)
+                    .map_err(error::AudioStream::Filter)?;
+                if !more {
+                    filter.drain(samples).map_err(error::AudioStream::Filter)?;
+                }
+            }
+            None => samples.extend_from_slice(&decoded),
+        }
+        self.finish_samples(&mut samples[start..], self.format.channels());
+
+        self.ended = !more;
+        self.decoded = decoded;
+        Ok(more)
+    }
+}
+
+impl Drop for AudioStream {
+    fn drop(&mut self) {
+        self.pool.put_samples(std::mem::take(&mut self.samples));
+        self.pool.put_samples(std::mem::take(&mut self.decoded));
+        self.pool.put_samples(std::mem::take(&mut self.pending));
+    }
+}
+
+/// Format of a track's audio as stored in its file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SourceFormat {
+    sample_rate: u32,
+    sample_format: ffm::format::Sample,
+
+    /// Bits of each sample that carry audio, if the file says.
+    bits_per_sample: Option<u32>,
+    channels: u16,
+
+    /// Empty if the file only gives the number of channels.
+    channel_layout: ffm::ChannelLayout,
+}
+
+impl SourceFormat {
+    fn of(decoder: &ffm::decoder::Audio) -> Self {
+        // SAFETY: the pointer is to the decoder's codec context, which lives as long as `decoder`.
+        let bits_per_sample = unsafe { (*decoder.as_ptr()).bits_per_raw_sample };
+        Self {
+            sample_rate: decoder.rate(),
+            sample_format: decoder.format(),
+            bits_per_sample: u32::try_from(bits_per_sample).ok().filter(|bits| *bits > 0),
+            channels: decoder.channels(),
+            channel_layout: decoder.channel_layout(),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn sample_format(&self) -> ffm::format::Sample {
+        self.sample_format
+    }
+
+    /// Bits of each sample that carry audio, e.g. `24` for 24 bit FLAC decoded to `i32` samples.
+    /// `None` if the file does not say.
+    pub fn bits_per_sample(&self) -> Option<u32> {
+        self.bits_per_sample
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    pub fn channel_layout(&self) -> ffm::ChannelLayout {
+        self.channel_layout
+    }
+}
+
+/// Format audio is decoded to for an [`AudioOutput`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DecodeFormat {
+    sample_rate: u32,
+
+    /// Layout audio is mixed to.
+    mix_layout: ffm::ChannelLayout,
+
+    /// Layout of the device's channels, the mix is spread over.
+    device_layout: ffm::ChannelLayout,
+
+    /// The device is configured to play the audio unchanged,
+    /// see [`AudioStreamBuilder::with_bit_perfect`].
+    bit_perfect: bool,
+}
+
+impl DecodeFormat {
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.device_layout.channels().max(1) as u16
+    }
+}
+
+/// Where a stream's audio comes from.
+enum Source {
+    /// Decoded from the track's file as it plays.
+    File(FileSource),
+
+    /// Played from audio decoded earlier.
+    Decoded {
+        track: Arc<DecodedTrack>,
+
+        /// Index of the next sample to play.
+        position: usize,
+    },
+}
+
+impl Source {
+    fn path(&self) -> &PathBuf {
+        match self {
+            Self::File(file) => file.audio_file.path(),
+            Self::Decoded { track, .. } => track.path(),
+        }
+    }
+
+    fn duration(&self) -> Option<Duration> {
+        match self {
+            Self::File(file) => file.duration,
+            Self::Decoded { track, .. } => track.duration(),
+        }
+    }
+
+    fn replay_gain(&self) -> ReplayGainTags {
+        match self {
+            Self::File(file) => file.replay_gain,
+            Self::Decoded { track, .. } => *track.replay_gain(),
+        }
+    }
+
+    fn source_format(&self) -> SourceFormat {
+        match self {
+            Self::File(file) => file.source_format,
+            Self::Decoded { track, .. } => track.source_format(),
+        }
+    }
+
+    /// Position of the end of the audio read so far.
+    fn position(&self) -> Duration {
+        match self {
+            Self::File(file) => file.position,
+            Self::Decoded { track, position } => track.position_of(*position),
+        }
+    }
+
+    /// Reads the next block of audio.
+    ///
+    /// # Arguments
+    /// + `samples`: Buffer the samples are appended to.
+    ///
+    /// # Returns
+    /// `false` once the end of the track is reached.
+    fn read(&mut self, samples: &mut Vec<f32>) -> Result<bool, error::AudioStream> {
+        match self {
+            Self::File(file) => file.decode_packet(samples),
+            Self::Decoded { track, position } => {
+                let block = &track.samples()[*position..];
+                let block = &block[..cmp::min(block.len(), DECODED_BLOCK_SAMPLES)];
+                samples.extend_from_slice(block);
+                *position += block.len();
+                Ok(*position < track.samples().len())
+            }
+        }
+    }
+
+    fn seek(&mut self, target: Duration) -> Result<(), error::AudioStream> {
+        match self {
+            Self::File(file) => file.seek(target),
+            Self::Decoded { track, position } => {
+                *position = track.sample_at(target);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Number of samples read at a time from audio decoded earlier.
+const DECODED_BLOCK_SAMPLES: usize = 8192;
+
+/// Decodes a track from its file, to the format of an output.
+struct FileSource {
+    audio_file: AudioFile,
+    stream_index: usize,
+    time_base: ffm::Rational,
+    decoder: ffm::decoder::Audio,
+
+    /// Format of the audio in the file.
+    source_format: SourceFormat,
+
+    /// Layout frames without one are taken to have.
+    file_layout: ffm::ChannelLayout,
+
+    /// Converts the decoded audio to the output's sample rate, layout, and `f32` samples.
+    /// `None` if it is already at the output's rate and layout, in a sample format
+    /// that converts to `f32` exactly, e.g. when the device is opened in the file's format.
+    resampler: Option<ffm::software::resampling::context::Context>,
+
+    /// Spreads a forced channel layout over the device's channels.
+    remixer: Option<ffm::software::resampling::context::Context>,
+    replay_gain: ReplayGainTags,
+    duration: Option<Duration>,
+
+    /// Position of the end of the last decoded frame.
+    position: Duration,
+
+    /// Position of the last seek.
+    /// Decoded audio before this is dropped.
+    seek_target: Option<Duration>,
+    decode_error_policy: DecodeErrorPolicy,
+
+    /// Packets of the track that could not be read or decoded.
+    decode_errors: u32,
+
+    /// Frames the resampler and remixer write to, reused for each packet.
+    resampled: ffm::frame::Audio,
+    remixed: ffm::frame::Audio,
+    pool: BufferPool,
+}
+
+impl FileSource {
+    fn open(
+        mut audio_file: AudioFile,
+        format: DecodeFormat,
+        decode_error_policy: DecodeErrorPolicy,
+        pool: &BufferPool,
+    ) -> Result<Self, ffm::Error> {
+        audio_file.ctx_mut().seek(0, ..0)?;
+
+        // Find the audio stream and its index
+        let audio_stream = audio_file
+            .ctx()
+            .streams()
+            .best(ffm::media::Type::Audio)
+            .ok_or(ffm::Error::StreamNotFound)?;
+
+        let audio_stream_index = audio_stream.index();
+        let time_base = audio_stream.time_base();
+        let replay_gain =
+            ReplayGainTags::from_metadata(&[audio_file.ctx().metadata(), audio_stream.metadata()]);
+        let duration = if audio_file.ctx().duration() > 0 {
+            // format context durations are in `AV_TIME_BASE` (microseconds)
+            Some(Duration::from_micros(audio_file.ctx().duration() as u64))
+        } else if audio_stream.duration() > 0 {
+            Some(Duration::from_secs_f64(
+                audio_stream.duration() as f64 * f64::from(time_base),
+            ))
+        } else {
+            None
+        };
+
+        // Create a decoder
+        let ctx = ffm::codec::Context::from_parameters(audio_stream.parameters())?;
+        let audio_decoder = ctx.decoder().audio()?;
+
+        // Some files don't specify a layout, only a channel count
+        let file_layout = if audio_decoder.channel_layout().is_empty() {
+            ffm::ChannelLayout::default(audio_decoder.channels() as i32)
+        } else {
+            audio_decoder.channel_layout()
+        };
+
+        // Set up a resampler for the audio,
+        // mixing it to the device's channels
+        let source_format = SourceFormat::of(&audio_decoder);
+        let sample_format = ffm::format::Sample::F32(ffm::format::sample::Type::Packed);
+        let bypass = audio_decoder.rate() == format.sample_rate
+            && file_layout == format.mix_layout
+            && format.mix_layout == format.device_layout
+            && converts_exactly(&source_format);
+        let resampler = if bypass {
+            None
+        } else {
+            if format.bit_perfect {
+                tracing::warn!(
+                    file = ?audio_file.path(),
+                    sample_rate = audio_decoder.rate(),
+                    sample_format = ?audio_decoder.format(),
+                    layout = ?file_layout,
+                    "audio is resampled, so not played bit-perfect"
+                );
+            }
+            Some(ffm::software::resampling::context::Context::get(
+                audio_decoder.format(),
+                file_layout,
+                audio_decoder.rate(),
+                sample_format,
+                format.mix_layout,
+                format.sample_rate,
+            )?)
+        };
+
+        // A forced layout still has to be spread over the device's channels
+        let remixer = if format.mix_layout != format.device_layout {
+            Some(ffm::software::resampling::context::Context::get(
+                sample_format,
+                format.mix_layout,
+                format.sample_rate,
+                sample_format,
+                format.device_layout,
+                format.sample_rate,
+            )?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            audio_file,
+            stream_index: audio_stream_index,
+            time_base,
+            source_format,
+            decoder: audio_decoder,
+            file_layout,
+            resampler,
+            remixer,
+            replay_gain,
+            duration,
+            position: Duration::ZERO,
+            seek_target: None,
+            decode_error_policy,
+            decode_errors: 0,
+            resampled: pool.take_frame(),
+            remixed: pool.take_frame(),
+            pool: pool.clone(),
+        })
+    }
+
+    fn seek(&mut self, target: Duration) -> Result<(), error::AudioStream> {
+        // Seek to the closest point before the target, decoded audio up to the target is dropped.
+        // Format context timestamps are in `AV_TIME_BASE` (microseconds).
+        let ts = i64::try_from(target.as_micros()).unwrap_or(i64::MAX);
+        self.audio_file
+            .ctx_mut()
+            .seek(ts, ..ts)
+            .map_err(error::AudioStream::Seek)?;
+
+        // Flush audio from before the seek
+        self.decoder.flush();
+        if let Some(resampler) = self.resampler.as_mut() {
+            let input = *resampler.input();
+            let resampled = *resampler.output();
+            *resampler = ffm::software::resampling::context::Context::get(
+                input.format,
+                input.channel_layout,
+                input.rate,
+                resampled.format,
+                resampled.channel_layout,
+                resampled.rate,
+            )
+            .map_err(error::AudioStream::Resample)?;
+        }
+
+        self.position = target;
+        let _ = self.seek_target.insert(target);
+        Ok(())
+    }
+
+    /// Decodes the next audio packet of the track.
+    ///
+    /// # Arguments
+    /// + `samples`: Buffer the decoded samples are appended to.
+    ///
+    /// # Returns
+    /// `false` once the end of the track is reached.
+    fn decode_packet(&mut self, samples: &mut Vec<f32>) -> Result<bool, error::AudioStream> {
+        let mut packet = ffm::Packet::empty();
+        loop {
+            match packet.read(self.audio_file.ctx_mut()) {
+                Ok(()) => {}
+                Err(ffm::Error::Eof) => {
+                    self.drain(samples)?;
+                    return Ok(false);
+                }
+                Err(err) => {
+                    self.skip_decode_error(err)?;
+                    continue;
+                }
+            }
+
+            // Look for audio packets (ignore video and others)
+            if packet.stream() == self.stream_index {
+                break;
+            }
+        }
+
+        // Send the packet to the decoder; it will combine them into frames.
+        // In practice though, 1 packet = 1 frame
+        if let Err(err) = self.decoder.send_packet(&packet) {
+            self.skip_decode_error(err)?;
+            return Ok(true);
+        }
+
+        self.receive_audio_frames(samples)?;
+        Ok(true)
+    }
+
+    /// Takes the audio still held by the decoder and resamplers
+    /// at the end of the track.
+    ///
+    /// # Arguments
+    /// + `samples`: Buffer the drained samples are appended to.
+    fn drain(&mut self, samples: &mut Vec<f32>) -> Result<(), error::AudioStream> {
+        self.decoder
+            .send_eof()
+            .map_err(error::AudioStream::Decode)?;
+        self.receive_audio_frames(samples)?;
+
+        let start = samples.len();
+        let flushed = match self.resampler.as_mut() {
+            Some(resampler) => flush_resampler(resampler).map_err(error::AudioStream::Resample)?,
+            None => None,
+        };
+        match (flushed, self.remixer.as_mut()) {
+            (Some(flushed), Some(remixer)) => {
+                let mut remixed = ffm::frame::Audio::empty();
+                remixer
+                    .run(&flushed, &mut remixed)
+                    .map_err(error::AudioStream::Resample)?;
+                samples.extend_from_slice(packed::<f32>(&remixed));
+            }
+            (Some(flushed), None) => samples.extend_from_slice(packed::<f32>(&flushed)),
+            (None, _) => {}
+        }
+        if let Some(remixer) = self.remixer.as_mut() {
+            if let Some(flushed) = flush_resampler(remixer).map_err(error::AudioStream::Resample)? {
+                samples.extend_from_slice(packed::<f32>(&flushed));
+            }
+        }
+
+        let Some(resampled) = self.remixer.as_ref().or(self.resampler.as_ref()) else {
+            // nothing held back without a resampler
+            return Ok(());
+        };
+        let resampled = *resampled.output();
+        let channels = resampled.channel_layout.channels().max(1) as usize;
+        self.position += Duration::from_secs_f64(
+            ((samples.len() - start) / channels) as f64 / resampled.rate as f64,
+        );
+        Ok(())
+    }
+
+    /// Number of samples at the start of a frame to drop, as they are before the seek target.
+    /// The target is cleared once a frame reaches it.
+    ///
+    /// # Arguments
+    /// + `timestamp`: Position of the frame.
+    /// + `len`: Number of samples in the frame.
+    fn seek_skip(
+        seek_target: &mut Option<Duration>,
+        timestamp: Option<Duration>,
+        rate: u32,
+        channels: u16,
+        len: usize,
+    ) -> usize {
+        let skip = match (*seek_target, timestamp) {
+            (Some(target), Some(ts)) if ts < target => {
+                let frames = ((target - ts).as_secs_f64() * rate as f64) as usize;
+                cmp::min(frames * channels as usize, len)
+            }
+            _ => 0,
+        };
+        if skip < len {
+            *seek_target = None;
+        }
+
+        skip
+    }
+
+    /// Skips audio that could not be read or decoded, if the error policy allows.
+    ///
+    /// # Returns
+    /// + `Err` if the track has had too many errors to keep playing.
+    fn skip_decode_error(&mut self, err: ffm::Error) -> Result<(), error::AudioStream> {
+        self.decode_errors += 1;
+        if self.decode_errors > self.decode_error_policy.max_errors {
+            return Err(error::AudioStream::Decode(err));
+        }
+
+        tracing::warn!(
+            ?err,
+            errors = self.decode_errors,
+            "skipping audio that could not be decoded"
+        );
+        Ok(())
+    }
+
+    fn receive_audio_frames(&mut self, samples: &mut Vec<f32>) -> Result<(), error::AudioStream> {
+        let mut decoded = ffm::frame::Audio::empty();
+
+        // Ask the decoder for frames
+        loop {
+            match self.decoder.receive_frame(&mut decoded) {
+                Ok(()) => {}
+                Err(ffm::Error::Eof) => break,
+                Err(ffm::Error::Other { errno }) if errno == ffm::error::EAGAIN => break,
+                Err(err) => {
+                    // drop the rest of the packet
+                    self.skip_decode_error(err)?;
+                    break;
+                }
+            }
+
+            let timestamp = decoded.timestamp().map(|ts| {
+                Duration::from_secs_f64((ts as f64 * f64::from(self.time_base)).max(0.0))
+            });
+            let frame_duration =
+                Duration::from_secs_f64(decoded.samples() as f64 / decoded.rate() as f64);
+
+            // Frames without a layout are taken to have the file's
+            if decoded.channel_layout().is_empty() {
+                decoded.set_channel_layout(self.file_layout);
+            }
+
+            let Some(resampler) = self.resampler.as_mut() else {
+                // already in the output's format
+                let start = samples.len();
+                extend_interleaved(&decoded, samples);
+                let skip = Self::seek_skip(
+                    &mut self.seek_target,
+                    timestamp,
+                    decoded.rate(),
+                    decoded.channels(),
+                    samples.len() - start,
+                );
+                samples.drain(start..start + skip);
+
+                self.position = timestamp.unwrap_or(self.position) + frame_duration;
+                continue;
+            };
+
+            // Resample the frame's audio into another frame
+            self.pool
+                .fit_frame(&mut self.resampled, resampler, decoded.samples());
+            resampler
+                .run(&decoded, &mut self.resampled)
+                .map_err(error::AudioStream::Resample)?;
+
+            let resampled = match self.remixer.as_mut() {
+                Some(remixer) => {
+                    self.pool
+                        .fit_frame(&mut self.remixed, remixer, self.resampled.samples());
+                    remixer
+                        .run(&self.resampled, &mut self.remixed)
+                        .map_err(error::AudioStream::Resample)?;
+                    &self.remixed
+                }
+                None => &self.resampled,
+            };
+
+            // DON'T just use resampled.data(0).len() -- it might not be fully populated
+            // Grab the right number of bytes based on sample count, bytes per sample, and number of channels.
+            let both_channels = packed::<f32>(&resampled);
+
+            // Drop audio before the seek target
+            let skip = Self::seek_skip(
+                &mut self.seek_target,
+                timestamp,
+                resampled.rate(),
+                resampled.channels(),
+                both_channels.len(),
+            );
+            self.position = timestamp.unwrap_or(self.position) + frame_duration;
+            samples.extend_from_slice(&both_channels[skip..]);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FileSource {
+    fn drop(&mut self) {
+        self.pool.put_frame(std::mem::replace(
+            &mut self.resampled,
+            ffm::frame::Audio::empty(),
+        ));
+        self.pool.put_frame(std::mem::replace(
+            &mut self.remixed,
+            ffm::frame::Audio::empty(),
+        ));
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum StreamState {
+    Play,
+    Pause,
+
+    /// Stream stopped before end.
+    Stop,
+
+    /// Stream loaded all the way through.
+    Done,
+}
+
+impl StreamState {
+    pub fn is_paused(&self) -> bool {
+        matches!(self, Self::Pause)
+    }
+
+    pub fn is_playing(&self) -> bool {
+        matches!(self, Self::Play)
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        matches!(self, Self::Stop)
+    }
+
+    pub fn is_done(&self) -> bool {
+        matches!(self, Self::Done)
+    }
+}
+
+/// How a stream handles audio that can not be read or decoded, e.g. from a corrupt file.
+#[derive(Copy, Clone, Debug)]
+pub struct DecodeErrorPolicy {
+    /// Number of bad packets skipped before the track is abandoned.
+    /// `0` abandons a track on its first error.
+    pub max_errors: u32,
+}
+
+impl Default for DecodeErrorPolicy {
+    fn default() -> Self {
+        Self { max_errors: 10 }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum Seek {
+    /// Seek to a position from the start of the track.
+    To(Duration),
+
+    /// Skip ahead from the current position.
+    Forward(Duration),
+
+    /// Skip back from the current position.
+    Backward(Duration),
+}
+
+/// Playback position of a stream, based on the samples consumed by its output.
+#[derive(Debug)]
+pub struct StreamClock {
+    /// Cursor of the output the stream is loaded into.
+    cursor: Arc<BufferCursor>,
+
+    /// Samples played per second, across all channels.
+    sample_rate: u64,
+    duration: Option<Duration>,
+
+    /// Position in the track at a consumed sample count.
+    /// Updated on seek and tempo changes.
+    marks: Mutex<ClockMarks>,
+}
+
+impl StreamClock {
+    fn new(cursor: Arc<BufferCursor>, sample_rate: u64, duration: Option<Duration>) -> Self {
+        Self {
+            cursor,
+            sample_rate,
+            duration,
+
+            // stream has not started until it queues audio
+            marks: Mutex::new(ClockMarks {
+                current: ClockMark {
+                    sample: u64::MAX,
+                    position: Duration::ZERO,
+                    tempo: 1.0,
+                },
+                previous: None,
+            }),
+        }
+    }
+
+    /// Elapsed time in the track.
+    pub fn position(&self) -> Duration {
+        let consumed = self.cursor.consumed.load(Ordering::Acquire);
+        let marks = self.marks.lock().unwrap();
+        let mark = match marks.previous {
+            // audio queued before the current mark is still playing
+            Some(previous) if consumed < marks.current.sample => previous,
+            _ => marks.current,
+        };
+
+        let position = mark.position_at(consumed, self.sample_rate);
+        match self.duration {
+            Some(duration) => cmp::min(position, duration),
+            None => position,
+        }
+    }
+
+    /// Total length of the track, if known.
+    pub fn duration(&self) -> Option<Duration> {
+        self.duration
+    }
+
+    /// Time left in the track, if its length is known.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.duration
+            .map(|duration| duration.saturating_sub(self.position()))
+    }
+
+    /// Marks the position of the track at a sample,
+    /// discarding any earlier marks.
+    fn set_mark(&self, sample: u64, position: Duration) {
+        let mut marks = self.marks.lock().unwrap();
+        marks.current.sample = sample;
+        marks.current.position = position;
+        marks.previous = None;
+    }
+
+    /// Changes the tempo from a sample on.
+    fn set_tempo(&self, sample: u64, tempo: f32) {
+        let mut marks = self.marks.lock().unwrap();
+        if marks.current.sample == u64::MAX {
+            // not started, so nothing played at the previous tempo
+            marks.current.tempo = tempo;
+            return;
+        }
+
+        let previous = marks.current;
+        marks.current = ClockMark {
+            sample,
+            position: previous.position_at(sample, self.sample_rate),
+            tempo,
+        };
+        marks.previous = Some(previous);
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct ClockMarks {
+    current: ClockMark,
+
+    /// Mark audio before the current mark is played from.
+    previous: Option<ClockMark>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct ClockMark {
+    /// Consumed sample count of the mark.
+    sample: u64,
+
+    /// Position in the track at the sample.
+    position: Duration,
+
+    /// Tempo of the audio from the sample on.
+    tempo: f32,
+}
+
+impl ClockMark {
+    /// Position in the track at a consumed sample count.
+    ///
+    /// # Arguments
+    /// + `sample_rate`: Samples played per second, across all channels.
+    fn position_at(&self, sample: u64, sample_rate: u64) -> Duration {
+        let played = sample.saturating_sub(self.sample);
+        self.position
+            + Duration::from_secs_f64(played as f64 * self.tempo as f64 / sample_rate as f64)
+    }
+}
+
+/// Counts samples passing through an output's ring buffer
+/// so queued audio can be discarded from the producer side.
+#[derive(Debug, Default)]
+struct BufferCursor {
+    /// Number of samples taken from the ring buffer by the output callback.
+    consumed: AtomicU64,
+
+    /// Samples pushed before this count are skipped by the output callback.
+    discard_until: AtomicU64,
+}
+
+/// Errors reported by an output's stream.
+#[derive(Debug, Default)]
+struct DeviceStatus {
+    /// The device is gone, e.g. unplugged.
+    lost: AtomicBool,
+
+    /// Errors reported since the device last asked for audio.
+    errors: AtomicU32,
+}
+
+// Interpret the audio frame's data as packed (alternating channels, 12121212, as opposed to planar 11112222)
+pub fn packed<T: ffm::frame::audio::Sample>(frame: &ffm::frame::Audio) -> &[T] {
+    if !frame.is_packed() {
+        panic!("data is not packed");
+    }
+
+    if !<T as ffm::frame::audio::Sample>::is_valid(frame.format(), frame.channels()) {
+        panic!("unsupported type");
+    }
+
+    unsafe {
+        std::slice::from_raw_parts(
+            (*frame.as_ptr()).data[0] as *const T,
+            frame.samples() * frame.channels() as usize,
+        )
+    }
+}
+
+/// Device sample format that plays samples of the given format unchanged.
+/// `None` for formats that never [convert exactly](converts_exactly)
+/// through the `f32` output buffer.
+fn device_sample_format(format: ffm::format::Sample) -> Option<cpal::SampleFormat> {
+    match format {
+        ffm::format::Sample::U8(_) => Some(cpal::SampleFormat::U8),
+        ffm::format::Sample::I16(_) => Some(cpal::SampleFormat::I16),
+        ffm::format::Sample::I32(_) => Some(cpal::SampleFormat::I32),
+        ffm::format::Sample::F32(_) => Some(cpal::SampleFormat::F32),
+        ffm::format::Sample::I64(_) | ffm::format::Sample::F64(_) | ffm::format::Sample::None => {
+            None
+        }
+    }
+}
+
+/// Whether a track's samples convert to `f32` and back to their format unchanged,
+/// so the resampler can be bypassed when the sample rate and layout already match.
+/// `f32` holds integers of up to 24 bits exactly, so `i32` samples only convert exactly
+/// if the file says they carry no more, e.g. 24 bit FLAC, but not 32 bit PCM.
+fn converts_exactly(source: &SourceFormat) -> bool {
+    match source.sample_format() {
+        ffm::format::Sample::U8(_) | ffm::format::Sample::I16(_) | ffm::format::Sample::F32(_) => {
+            true
+        }
+        ffm::format::Sample::I32(_) => source.bits_per_sample().is_some_and(|bits| bits <= 24),
+        ffm::format::Sample::I64(_) | ffm::format::Sample::F64(_) | ffm::format::Sample::None => {
+            false
+        }
+    }
+}
+
+/// Appends a frame's audio to `samples` as interleaved `f32`, without resampling it.
+/// `i32` samples of more than 24 bits are rounded.
+///
+/// # Panics
+/// If the frame's samples do not [convert exactly](converts_exactly).
+fn extend_interleaved(frame: &ffm::frame::Audio, samples: &mut Vec<f32>) {
+    match frame.format() {
+        ffm::format::Sample::U8(_) => interleave::<u8>(frame, samples),
+        ffm::format::Sample::I16(_) => interleave::<i16>(frame, samples),
+        ffm::format::Sample::I32(_) => interleave::<i32>(frame, samples),
+        ffm::format::Sample::F32(_) => interleave::<f32>(frame, samples),
+        format => panic!("{format:?} samples must be resampled"),
+    }
+}
+
+fn interleave<T>(frame: &ffm::frame::Audio, samples: &mut Vec<f32>)
+where
+    T: ffm::frame::audio::Sample + cpal::Sample,
+    f32: cpal::FromSample<T>,
+{
+    if frame.is_packed() {
+        samples.extend(
+            packed::<T>(frame)
+                .iter()
+                .map(|sample| sample.to_sample::<f32>()),
+        );
+        return;
+    }
+
+    let planes = (0..frame.planes())
+        .map(|plane| frame.plane::<T>(plane))
+        .collect::<Vec<_>>();
+    samples.reserve(frame.samples() * planes.len());
+    for idx in 0..frame.samples() {
+        samples.extend(planes.iter().map(|plane| plane[idx].to_sample::<f32>()));
+    }
+}
+
+/// Takes the audio still held by a resampler, e.g. at the end of a track.
+fn flush_resampler(
+    resampler: &mut ffm::software::resampling::context::Context,
+) -> Result<Option<ffm::frame::Audio>, ffm::Error> {
+    let Some(delay) = resampler.delay() else {
+        return Ok(None);
+    };
+
+    let output = *resampler.output();
+    let mut flushed = ffm::frame::Audio::new(
+        output.format,
+        delay.output.max(1) as usize,
+        output.channel_layout,
+    );
+    resampler.flush(&mut flushed)?;
+    Ok((flushed.samples() > 0).then_some(flushed))
+}
+
+/// State moved into the output stream's callback.
+struct OutputCallback {
+    buffer_cons: BufferCons<f32>,
+    channels: u16,
+    cursor: Arc<BufferCursor>,
+    gain: volume::GainRamp,
+    fade: volume::FadeRamp,
+
+    /// Wakes the thread feeding the output.
+    wake: channel::Sender<()>,
+    underruns: Arc<stats::UnderrunCounter>,
+    device: Arc<DeviceStatus>,
+}
+
+fn write_audio<T: cpal::Sample + cpal::FromSample<f32>>(
+    data: &mut [T],
+    callback: &mut OutputCallback,
+    _: &cpal::OutputCallbackInfo,
+) {
+    let samples = &mut callback.buffer_cons;
+    let cursor = &callback.cursor;
+    let mut consumed = cursor.consumed.load(Ordering::Relaxed);
+    let discard_until = cursor.discard_until.load(Ordering::Acquire);
+    let expecting = callback.underruns.is_expecting();
+    let mut missing = 0;
+
+    for frame in data.chunks_mut(callback.channels.max(1) as usize) {
+        // fade out before jumping over discarded audio, then back in
+        let discarding = consumed < discard_until;
+        let fade = callback.fade.next(discarding);
+        if discarding && fade == 0.0 {
+            // drop audio queued before a seek
+            consumed += samples.skip((discard_until - consumed) as usize) as u64;
+        }
+
+        if callback.fade.is_held() {
+            // paused, keep the queued audio for when playback resumes
+            frame.fill(cpal::Sample::EQUILIBRIUM);
+            continue;
+        }
+
+        let gain = callback.gain.next() * fade;
+        let mut ran_dry = false;
+        for d in frame {
+            // copy as many samples as we have.
+            // if we run out, write silence
+            match samples.try_pop() {
+                Some(sample) => {
+                    *d = T::from_sample(sample * gain);
+                    consumed += 1;
+                }
+                None => {
+                    *d = cpal::Sample::EQUILIBRIUM;
+                    ran_dry = true;
+                }
+            }
+        }
+
+        if ran_dry && expecting && !discarding {
+            missing += 1;
+        }
+    }
+
+    cursor.consumed.store(consumed, Ordering::Release);
+    if missing > 0 {
+        callback.underruns.record(missing);
+    }
+}
+
+pub mod error {
+    use ffmpeg_next as ffm;
+
+    #[derive(Debug)]
+    pub struct InvalidIndex;
+
+    #[derive(Debug)]
+    pub enum AudioOutput {
+        /// The device's sample format can not be played to.
+        UnsupportedFormat(cpal::SampleFormat),
+
+        /// The device can not ask for audio in blocks of the requested number of frames.
+        UnsupportedPeriodSize {
+            period: u32,
+            min: u32,
+            max: u32,
+        },
+        Build(cpal::BuildStreamError),
+    }
+
+    /// Why a track can not be played bit-perfect.
+    #[derive(Debug)]
+    pub enum BitPerfect {
+        /// The file's sample format has no matching device format,
+        /// or does not fit the output buffer exactly.
+        UnsupportedFormat(ffm::format::Sample),
+
+        /// The file's samples carry more bits than the output buffer holds exactly,
+        /// e.g. 32 bit PCM. `None` if the file does not say how many bits they carry.
+        SampleBits(Option<u32>),
+
+        /// The file's channels are laid out other than the device's, so would be remixed.
+        FileLayout(ffm::ChannelLayout),
+
+        /// Audio is mixed to a forced layout other than the file's.
+        ChannelLayout(ffm::ChannelLayout),
+
+        /// The device has no config in the file's format.
+        NoConfig {
+            sample_rate: u32,
+            channels: u16,
+            sample_format: cpal::SampleFormat,
+        },
+        Device(cpal::SupportedStreamConfigsError),
+    }
+
+    impl From<cpal::BuildStreamError> for AudioOutput {
+        fn from(value: cpal::BuildStreamError) -> Self {
+            Self::Build(value)
+        }
+    }
+
+    #[derive(Debug)]
+    pub enum AudioStream {
+        /// The file could not be opened, or has no audio to decode.
+        Open(ffm::Error),
+        Resample(ffm::Error),
+        Decode(ffm::util::error::Error),
+        Seek(ffm::Error),
+        Filter(ffm::Error),
+        DeviceNotAvailable,
+        Other(String),
+    }
+
+    impl From<cpal::PlayStreamError> for AudioStream {
+        fn from(value: cpal::PlayStreamError) -> Self {
+            match value {
+                cpal::PlayStreamError::DeviceNotAvailable => Self::DeviceNotAvailable,
+                cpal::PlayStreamError::BackendSpecific { err } => Self::Other(err.description),
+            }
+        }
+    }
+
+    impl From<cpal::PauseStreamError> for AudioStream {
+        fn from(value: cpal::PauseStreamError) -> Self {
+            match value {
+                cpal::PauseStreamError::DeviceNotAvailable => Self::DeviceNotAvailable,
+                cpal::PauseStreamError::BackendSpecific { err } => Self::Other(err.description),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_is_continuous_across_tempo_mark() {
+        let cursor = Arc::new(BufferCursor::default());
+        let clock = StreamClock::new(cursor.clone(), 1_000, None);
+        let position_at = |consumed: u64| {
+            cursor.consumed.store(consumed, Ordering::Release);
+            clock.position()
+        };
+
+        clock.set_mark(0, Duration::ZERO);
+        assert_eq!(position_at(1_000), Duration::from_secs(1));
+
+        // audio queued before the change still plays at the old tempo
+        clock.set_tempo(2_000, 2.0);
+        assert_eq!(position_at(1_500), Duration::from_millis(1_500));
+        let before = position_at(1_999);
+        let at = position_at(2_000);
+        assert!(before < at && at - before <= Duration::from_millis(1));
+        assert_eq!(at, Duration::from_secs(2));
+
+        // then moves at the new tempo
+        assert_eq!(position_at(3_000), Duration::from_secs(4));
+        assert_eq!(position_at(3_500), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn tempo_before_start_applies_from_first_mark() {
+        let cursor = Arc::new(BufferCursor::default());
+        let clock = StreamClock::new(cursor.clone(), 1_000, None);
+        clock.set_tempo(0, 0.5);
+        clock.set_mark(500, Duration::from_secs(10));
+
+        cursor.consumed.store(1_500, Ordering::Release);
+        assert_eq!(clock.position(), Duration::from_millis(10_500));
     }
 }
@@ -0,0 +1,612 @@
+//! Loudness analysis following EBU R128 (ITU-R BS.1770).
+use crate::{biquad::Biquad, packed, AudioFile, DecodeErrorPolicy};
+use ffmpeg_next as ffm;
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+/// Loudness ReplayGain normalizes to, in LUFS.
+pub const REPLAY_GAIN_REFERENCE: f64 = -18.0;
+
+/// Blocks quieter than this are ignored, in LUFS.
+const ABSOLUTE_GATE: f64 = -70.0;
+
+/// Blocks this much quieter than the ungated loudness are ignored for the integrated loudness, in LU.
+const INTEGRATED_RELATIVE_GATE: f64 = -10.0;
+
+/// Blocks this much quieter than the ungated loudness are ignored for the loudness range, in LU.
+const RANGE_RELATIVE_GATE: f64 = -20.0;
+
+/// Loudness is measured over 100 ms segments, combined into blocks.
+const SEGMENTS_PER_SECOND: u32 = 10;
+
+/// Length of the blocks of the integrated loudness, in segments (400 ms).
+const MOMENTARY_SEGMENTS: usize = 4;
+
+/// Length of the blocks of the loudness range, in segments (3 s).
+const SHORT_TERM_SEGMENTS: usize = 30;
+
+/// Factor true peaks are oversampled by.
+const OVERSAMPLING: usize = 4;
+
+/// Input samples each phase of the oversampling filter uses.
+const TAPS_PER_PHASE: usize = 12;
+
+/// Loudness of a track.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Loudness {
+    /// Integrated loudness, in LUFS.
+    /// `-inf` if the track is silent.
+    pub integrated: f64,
+
+    /// Loudness range, in LU.
+    pub range: f64,
+
+    /// True peak, in dBTP.
+    pub true_peak: f64,
+}
+
+impl Loudness {
+    /// Measures the loudness of a whole file.
+    /// Packets that can not be read or decoded are skipped, as the policy allows.
+    pub fn analyze(
+        mut audio_file: AudioFile,
+        decode_error_policy: DecodeErrorPolicy,
+    ) -> Result<Loudness, ffm::Error> {
+        let audio_stream = audio_file
+            .ctx()
+            .streams()
+            .best(ffm::media::Type::Audio)
+            .ok_or(ffm::Error::StreamNotFound)?;
+
+        let stream_index = audio_stream.index();
+        let ctx = ffm::codec::Context::from_parameters(audio_stream.parameters())?;
+        let mut decoder = ctx.decoder().audio()?;
+
+        let layout = if decoder.channel_layout().is_empty() {
+            ffm::ChannelLayout::default(decoder.channels() as i32)
+        } else {
+            decoder.channel_layout()
+        };
+        let mut resampler = ffm::software::resampling::context::Context::get(
+            decoder.format(),
+            layout,
+            decoder.rate(),
+            ffm::format::Sample::F32(ffm::format::sample::Type::Packed),
+            layout,
+            decoder.rate(),
+        )?;
+
+        let mut meter = LoudnessMeter::new(decoder.rate(), layout.channels() as u16);
+        let mut measure = |decoder: &mut ffm::decoder::Audio| -> Result<(), ffm::Error> {
+            let mut decoded = ffm::frame::Audio::empty();
+            while decoder.receive_frame(&mut decoded).is_ok() {
+                if decoded.channel_layout().is_empty() {
+                    decoded.set_channel_layout(layout);
+                }
+
+                let mut resampled = ffm::frame::Audio::empty();
+                resampler.run(&decoded, &mut resampled)?;
+                meter.push(packed::<f32>(&resampled));
+            }
+            Ok(())
+        };
+
+        let mut decode_errors = 0;
+        let mut skip_decode_error = |err: ffm::Error| -> Result<(), ffm::Error> {
+            decode_errors += 1;
+            if decode_errors > decode_error_policy.max_errors {
+                return Err(err);
+            }
+
+            tracing::warn!(
+                ?err,
+                errors = decode_errors,
+                "skipping audio that could not be decoded"
+            );
+            Ok(())
+        };
+
+        let mut packet = ffm::Packet::empty();
+        loop {
+            match packet.read(audio_file.ctx_mut()) {
+                Ok(()) => {}
+                Err(ffm::Error::Eof) => break,
+                Err(err) => {
+                    skip_decode_error(err)?;
+                    continue;
+                }
+            }
+
+            if packet.stream() == stream_index {
+                if let Err(err) = decoder.send_packet(&packet) {
+                    skip_decode_error(err)?;
+                    continue;
+                }
+                measure(&mut decoder)?;
+            }
+        }
+
+        decoder.send_eof()?;
+        measure(&mut decoder)?;
+        Ok(meter.finish())
+    }
+}
+
+/// Measures the loudness of audio.
+pub struct LoudnessMeter {
+    /// Weight of each channel's power.
+    weights: Vec<f64>,
+
+    /// K-weighting filters of each channel.
+    filters: Vec<[Biquad; 2]>,
+
+    /// Frames in a segment.
+    segment_frames: usize,
+
+    /// Frames in the current segment so far.
+    segment_progress: usize,
+
+    /// Weighted power of the current segment so far.
+    segment_power: f64,
+
+    /// Mean weighted power of each finished segment.
+    segments: Vec<f64>,
+
+    /// Last samples of each channel, for the oversampling filter.
+    history: Vec<[f64; TAPS_PER_PHASE]>,
+    history_pos: usize,
+
+    /// Oversampling filter, interleaved by phase.
+    oversampling_filter: [f64; TAPS_PER_PHASE * OVERSAMPLING],
+    peak: f64,
+}
+
+impl LoudnessMeter {
+    /// # Arguments
+    /// + `channels`: Number of channels of the audio.
+    ///     Six channels are taken to be 5.1, whose LFE is ignored and surrounds are boosted.
+    pub fn new(sample_rate: u32, channels: u16) -> Self {
+        let channels = channels.max(1) as usize;
+        let weights = if channels == 6 {
+            vec![1.0, 1.0, 1.0, 0.0, 1.41, 1.41]
+        } else {
+            vec![1.0; channels]
+        };
+
+        Self {
+            weights,
+            filters: vec![k_weighting(sample_rate as f64); channels],
+            segment_frames: (sample_rate / SEGMENTS_PER_SECOND).max(1) as usize,
+            segment_progress: 0,
+            segment_power: 0.0,
+            segments: Vec::new(),
+            history: vec![[0.0; TAPS_PER_PHASE]; channels],
+            history_pos: 0,
+            oversampling_filter: oversampling_filter(),
+            peak: 0.0,
+        }
+    }
+
+    /// Measures interleaved samples.
+    pub fn push(&mut self, samples: &[f32]) {
+        let channels = self.weights.len();
+        for frame in samples.chunks_exact(channels) {
+            self.history_pos = (self.history_pos + 1) % TAPS_PER_PHASE;
+            for (channel, sample) in frame.iter().enumerate() {
+                let sample = *sample as f64;
+                let [shelf, highpass] = &mut self.filters[channel];
+                let weighted = highpass.process(shelf.process(sample));
+                self.segment_power += self.weights[channel] * weighted * weighted;
+
+                self.history[channel][self.history_pos] = sample;
+                self.peak = self.peak.max(self.true_peak(channel));
+            }
+
+            self.segment_progress += 1;
+            if self.segment_progress == self.segment_frames {
+                self.segments
+                    .push(self.segment_power / self.segment_frames as f64);
+                self.segment_progress = 0;
+                self.segment_power = 0.0;
+            }
+        }
+    }
+
+    /// Loudness of the audio measured.
+    /// Audio of the last partial segment is not included.
+    pub fn finish(self) -> Loudness {
+        let momentary = blocks(&self.segments, MOMENTARY_SEGMENTS);
+        let integrated = integrated_loudness(&momentary).unwrap_or(f64::NEG_INFINITY);
+
+        let short_term = blocks(&self.segments, SHORT_TERM_SEGMENTS);
+        let range = loudness_range(&short_term);
+
+        Loudness {
+            integrated,
+            range,
+            true_peak: 20.0 * self.peak.log10(),
+        }
+    }
+
+    /// Peak of the oversampled audio around the latest sample of a channel.
+    fn true_peak(&self, channel: usize) -> f64 {
+        let history = &self.history[channel];
+        (0..OVERSAMPLING)
+            .map(|phase| {
+                (0..TAPS_PER_PHASE)
+                    .map(|tap| {
+                        let idx = (self.history_pos + TAPS_PER_PHASE - tap) % TAPS_PER_PHASE;
+                        history[idx] * self.oversampling_filter[tap * OVERSAMPLING + phase]
+                    })
+                    .sum::<f64>()
+                    .abs()
+            })
+            .fold(history[self.history_pos].abs(), f64::max)
+    }
+}
+
+/// Analyzed loudness of files, so files only need to be analyzed once.
+/// Entries are dropped if their file changes.
+#[derive(Default, Debug)]
+pub struct LoudnessCache {
+    entries: HashMap<PathBuf, CacheEntry>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct CacheEntry {
+    /// Modification time of the file when analyzed, in seconds since the epoch.
+    modified: u64,
+
+    /// Size of the file when analyzed, in bytes.
+    size: u64,
+    loudness: Loudness,
+}
+
+impl LoudnessCache {
+    /// Loads a cache from a file.
+    /// A missing file gives an empty cache, and invalid lines are skipped.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err),
+        };
+
+        let mut entries = HashMap::new();
+        for line in io::BufReader::new(file).lines() {
+            if let Some((path, entry)) = CacheEntry::from_line(&line?) {
+                entries.insert(path, entry);
+            }
+        }
+
+        Ok(Self { entries })
+    }
+
+    /// Writes the cache to a file, creating its folder if needed.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = io::BufWriter::new(fs::File::create(path)?);
+        for (path, entry) in self.entries.iter() {
+            let Some(path) = path.to_str() else {
+                continue;
+            };
+
+            writeln!(
+                file,
+                "{}\t{}\t{}\t{}\t{}\t{}",
+                path,
+                entry.modified,
+                entry.size,
+                entry.loudness.integrated,
+                entry.loudness.range,
+                entry.loudness.true_peak,
+            )?;
+        }
+
+        file.flush()
+    }
+
+    /// Loudness of a file, if it was analyzed and has not changed since.
+    pub fn get(&self, path: impl AsRef<Path>) -> Option<Loudness> {
+        let path = path.as_ref();
+        let entry = self.entries.get(path)?;
+        let (modified, size) = file_stamp(path).ok()?;
+        (entry.modified == modified && entry.size == size).then_some(entry.loudness)
+    }
+
+    /// Loudness of a file, analyzing it if it is not cached.
+    ///
+    /// # Arguments
+    /// + `force`: Analyze the file even if it is cached.
+    pub fn analyze(
+        &mut self,
+        path: &Path,
+        force: bool,
+        decode_error_policy: DecodeErrorPolicy,
+    ) -> Result<Loudness, ffm::Error> {
+        if let Some(loudness) = self.get(path).filter(|_| !force) {
+            return Ok(loudness);
+        }
+
+        let audio_file = AudioFile::from_path(path.to_path_buf())?;
+        let loudness = Loudness::analyze(audio_file, decode_error_policy)?;
+        if let Err(err) = self.insert(path.to_path_buf(), loudness) {
+            tracing::debug!(?err);
+        }
+
+        Ok(loudness)
+    }
+
+    /// Records the loudness of a file as it currently is.
+    pub fn insert(&mut self, path: PathBuf, loudness: Loudness) -> io::Result<()> {
+        let (modified, size) = file_stamp(&path)?;
+        self.entries.insert(
+            path,
+            CacheEntry {
+                modified,
+                size,
+                loudness,
+            },
+        );
+
+        Ok(())
+    }
+}
+
+impl CacheEntry {
+    /// Parses a line of the form `path\tmodified\tsize\tintegrated\trange\ttrue peak`.
+    fn from_line(line: &str) -> Option<(PathBuf, Self)> {
+        // split from the end as paths may contain tabs
+        let mut fields = line.rsplitn(6, '\t');
+        let true_peak = fields.next()?.parse().ok()?;
+        let range = fields.next()?.parse().ok()?;
+        let integrated = fields.next()?.parse().ok()?;
+        let size = fields.next()?.parse().ok()?;
+        let modified = fields.next()?.parse().ok()?;
+        let path = PathBuf::from(fields.next()?);
+
+        Some((
+            path,
+            Self {
+                modified,
+                size,
+                loudness: Loudness {
+                    integrated,
+                    range,
+                    true_peak,
+                },
+            },
+        ))
+    }
+}
+
+/// # Returns
+/// (modification time in seconds since the epoch, size in bytes)
+fn file_stamp(path: &Path) -> io::Result<(u64, u64)> {
+    let metadata = fs::metadata(path)?;
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|modified| modified.as_secs())
+        .unwrap_or_default();
+
+    Ok((modified, metadata.len()))
+}
+
+/// Stages of the K-weighting filter: a high shelf, then a high pass.
+/// Coefficients are derived for the sample rate, matching BS.1770 at 48 kHz.
+fn k_weighting(sample_rate: f64) -> [Biquad; 2] {
+    use std::f64::consts::PI;
+
+    let f0 = 1681.974450955533;
+    let gain = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / sample_rate).tan();
+    let vh = 10_f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / sample_rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let highpass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    [shelf, highpass]
+}
+
+/// Windowed sinc low pass at the original Nyquist frequency,
+/// interpolating between samples when oversampling.
+fn oversampling_filter() -> [f64; TAPS_PER_PHASE * OVERSAMPLING] {
+    use std::f64::consts::PI;
+
+    let len = TAPS_PER_PHASE * OVERSAMPLING;
+    let center = (len - 1) as f64 / 2.0;
+    let mut filter = [0.0; TAPS_PER_PHASE * OVERSAMPLING];
+    for (n, coef) in filter.iter_mut().enumerate() {
+        let t = (n as f64 - center) / OVERSAMPLING as f64;
+        let sinc = if t == 0.0 {
+            1.0
+        } else {
+            (PI * t).sin() / (PI * t)
+        };
+
+        // Hann window
+        let window = 0.5 - 0.5 * (2.0 * PI * (n as f64 + 0.5) / len as f64).cos();
+        *coef = sinc * window;
+    }
+
+    filter
+}
+
+/// Mean power of overlapping blocks of segments, stepping one segment at a time.
+fn blocks(segments: &[f64], length: usize) -> Vec<f64> {
+    segments
+        .windows(length)
+        .map(|block| block.iter().sum::<f64>() / length as f64)
+        .collect()
+}
+
+/// Loudness of a mean power, in LUFS.
+fn loudness(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+/// Mean of powers.
+fn mean_power(powers: impl Iterator<Item = f64>) -> Option<f64> {
+    let (sum, count) = powers.fold((0.0, 0), |(sum, count), power| (sum + power, count + 1));
+    (count > 0).then(|| sum / count as f64)
+}
+
+/// Loudness blocks must be above to be measured, in LUFS.
+/// Combines the absolute gate and the relative gate derived from the blocks passing it.
+///
+/// # Returns
+/// `None` if no blocks pass the absolute gate.
+fn gate_threshold(blocks: &[f64], relative_gate: f64) -> Option<f64> {
+    let gated = blocks
+        .iter()
+        .copied()
+        .filter(|power| loudness(*power) > ABSOLUTE_GATE);
+
+    let relative = loudness(mean_power(gated)?) + relative_gate;
+    Some(relative.max(ABSOLUTE_GATE))
+}
+
+/// Gated loudness of momentary blocks, in LUFS.
+fn integrated_loudness(blocks: &[f64]) -> Option<f64> {
+    let threshold = gate_threshold(blocks, INTEGRATED_RELATIVE_GATE)?;
+    mean_power(
+        blocks
+            .iter()
+            .copied()
+            .filter(|power| loudness(*power) > threshold),
+    )
+    .map(loudness)
+}
+
+/// Spread between the 10th and 95th percentile of the gated short term loudness, in LU.
+fn loudness_range(blocks: &[f64]) -> f64 {
+    let Some(threshold) = gate_threshold(blocks, RANGE_RELATIVE_GATE) else {
+        return 0.0;
+    };
+
+    let mut levels = blocks
+        .iter()
+        .map(|power| loudness(*power))
+        .filter(|level| *level > threshold)
+        .collect::<Vec<_>>();
+
+    if levels.is_empty() {
+        return 0.0;
+    }
+
+    levels.sort_by(f64::total_cmp);
+    let percentile = |p: f64| levels[((levels.len() - 1) as f64 * p).round() as usize];
+    percentile(0.95) - percentile(0.10)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48_000;
+
+    /// Stereo sine of 997 Hz, with a peak of `level` dBFS in both channels.
+    fn sine(level: f64, seconds: f64) -> Vec<f32> {
+        let amplitude = 10_f64.powf(level / 20.0);
+        let frames = (seconds * SAMPLE_RATE as f64) as usize;
+        (0..frames)
+            .flat_map(|n| {
+                let t = n as f64 / SAMPLE_RATE as f64;
+                let sample = (amplitude * (2.0 * std::f64::consts::PI * 997.0 * t).sin()) as f32;
+                [sample, sample]
+            })
+            .collect()
+    }
+
+    /// Segments of a signal made of parts of constant loudness.
+    ///
+    /// # Arguments
+    /// + `parts`: Loudness, in LUFS, and length, in seconds, of each part.
+    fn segments(parts: &[(f64, f64)]) -> Vec<f64> {
+        parts
+            .iter()
+            .flat_map(|(level, seconds)| {
+                let power = 10_f64.powf((level + 0.691) / 10.0);
+                let count = (seconds * SEGMENTS_PER_SECOND as f64).round() as usize;
+                std::iter::repeat_n(power, count)
+            })
+            .collect()
+    }
+
+    fn integrated(parts: &[(f64, f64)]) -> f64 {
+        let momentary = blocks(&segments(parts), MOMENTARY_SEGMENTS);
+        integrated_loudness(&momentary).unwrap()
+    }
+
+    #[test]
+    fn sine_reads_its_level() {
+        // EBU Tech 3341, cases 1 and 2
+        for level in [-23.0, -33.0] {
+            let mut meter = LoudnessMeter::new(SAMPLE_RATE, 2);
+            meter.push(&sine(level, 5.0));
+            let loudness = meter.finish();
+
+            assert!((loudness.integrated - level).abs() < 0.1, "{loudness:?}");
+            assert!(loudness.range.abs() < 0.1, "{loudness:?}");
+            assert!((loudness.true_peak - level).abs() < 0.2, "{loudness:?}");
+        }
+    }
+
+    #[test]
+    fn silence_is_gated() {
+        let mut meter = LoudnessMeter::new(SAMPLE_RATE, 2);
+        meter.push(&vec![0.0; SAMPLE_RATE as usize * 2]);
+        assert_eq!(meter.finish().integrated, f64::NEG_INFINITY);
+    }
+
+    #[test]
+    fn quiet_parts_are_gated() {
+        // EBU Tech 3341, case 3
+        let level = integrated(&[(-36.0, 10.0), (-23.0, 60.0), (-36.0, 10.0)]);
+        assert!((level - -23.0).abs() < 0.1, "{level}");
+
+        // EBU Tech 3341, case 4
+        let level = integrated(&[
+            (-72.0, 10.0),
+            (-36.0, 10.0),
+            (-23.0, 60.0),
+            (-36.0, 10.0),
+            (-72.0, 10.0),
+        ]);
+        assert!((level - -23.0).abs() < 0.1, "{level}");
+    }
+
+    #[test]
+    fn loud_parts_are_not_gated() {
+        // EBU Tech 3341, case 5
+        let level = integrated(&[(-26.0, 20.0), (-20.0, 20.1), (-26.0, 20.0)]);
+        assert!((level - -23.0).abs() < 0.1, "{level}");
+    }
+}
@@ -6,13 +6,46 @@
 //! + `k`: play next track
 //! + `j`: play previous track
 //! + `r`: restart
+//! + `f [time]`: seek forward (default 10 seconds)
+//! + `b [time]`: seek back (default 10 seconds)
+//! + `seek <time>`: seek to a position, or relative to the current position if prefixed with `+`/`-`.
+//!     Time is given as `[[hh:]mm:]ss`.
 //! + `l`: toggle looping
 //! + `a`: toggle autoplay
-//! + `s`: toggle show state
+//! + `s`: toggle show state (playlist and track progress)
+//! + `x`: toggle crossfade
+//! + `+`: volume up
+//! + `-`: volume down
+//! + `v <percent>`: set volume
+//! + `m`: toggle mute
+//! + `g`: cycle ReplayGain mode (off, track, album)
+//! + `]`: speed up
+//! + `[`: slow down
+//! + `t <tempo>`: set speed, e.g. `t 1.5`
+//! + `filter [filters]`: set the ffmpeg filters songs are run through, e.g. `filter highpass=f=200,acompressor`.
+//!     Removes the filters if none are given.
+//! + `eq [preset]`: equalize songs with a preset from the settings file.
+//!     Lists the presets if none is given.
+//! + `e`: toggle equalizer bypass
+//! + `stats`: show playback health (buffer underruns, buffer fill, decode speed, and memory reuse)
+//! + `device [name]`: play through another output device, picking up where the song was.
+//!     Lists the devices if none is given.
 //!
 //! # References
 //! + https://github.com/dceddia/ffmpeg-cpal-play-audio
 //! + https://www.bekk.christmas/post/2023/19/make-some-noise-with-rust
+macro_rules! write_trace {
+    ($dst:expr, $($arg:tt)*) => {
+        if let Err(err) = $dst.write_fmt(std::format_args!($($arg)*)) {
+            tracing::error!(?err);
+        }
+    };
+}
+
+mod analyze;
+mod cli;
+mod config;
+mod device;
 mod input_actor;
 mod player_actor;
 
@@ -24,25 +57,57 @@ use std::{
     cmp, env, fs,
     io::{self, Write},
     path::{Path, PathBuf},
+    time::Duration,
 };
 
-macro_rules! write_trace {
-    ($dst:expr, $($arg:tt)*) => {
-        if let Err(err) = $dst.write_fmt(std::format_args!($($arg)*)) {
-            tracing::error!(?err);
-        }
-    };
-}
+/// Length of audio buffered ahead of playback,
+/// unless given on the command line or in the settings file.
+const DEFAULT_AUDIO_BUFFER: Duration = Duration::from_millis(100);
 
-const AUDIO_BUFFER_SIZE: usize = 8192;
+/// Memory used to keep decoded songs, in megabytes,
+/// unless given on the command line.
+const DEFAULT_CACHE_SIZE: usize = 256;
 const CMD_KEY_QUIT: &str = "q";
 const CMD_KEY_PREVIOUS: &str = "j";
 const CMD_KEY_NEXT: &str = "k";
 const CMD_KEY_RESTART: &str = "r";
+const CMD_KEY_SEEK: &str = "seek";
+const CMD_KEY_SEEK_FORWARD: &str = "f";
+const CMD_KEY_SEEK_BACK: &str = "b";
 const CMD_KEY_TOGGLE_PLAY: &str = "p";
 const CMD_KEY_TOGGLE_LOOP: &str = "l";
 const CMD_KEY_TOGGLE_AUTOPLAY: &str = "a";
 const CMD_KEY_TOGGLE_SHOW_STATE: &str = "s";
+const CMD_KEY_TOGGLE_CROSSFADE: &str = "x";
+const CMD_KEY_VOLUME_UP: &str = "+";
+const CMD_KEY_VOLUME_DOWN: &str = "-";
+const CMD_KEY_VOLUME: &str = "v";
+const CMD_KEY_TOGGLE_MUTE: &str = "m";
+const CMD_KEY_CYCLE_REPLAY_GAIN: &str = "g";
+const CMD_KEY_TEMPO_UP: &str = "]";
+const CMD_KEY_TEMPO_DOWN: &str = "[";
+const CMD_KEY_TEMPO: &str = "t";
+const CMD_KEY_FILTER: &str = "filter";
+const CMD_KEY_EQ: &str = "eq";
+const CMD_KEY_TOGGLE_EQ: &str = "e";
+const CMD_KEY_STATS: &str = "stats";
+const CMD_KEY_DEVICE: &str = "device";
+
+/// Default offset for seeking forward and back.
+const SEEK_STEP: Duration = Duration::from_secs(10);
+
+/// Crossfade length used if crossfading is turned on at runtime,
+/// but no length was given.
+const DEFAULT_CROSSFADE: Duration = Duration::from_secs(5);
+
+/// Change in volume of the volume up and down commands.
+const VOLUME_STEP: f32 = 0.05;
+
+/// Change in tempo of the speed up and slow down commands.
+const TEMPO_STEP: f32 = 0.1;
+
+/// How often the progress line is redrawn.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
 
 #[derive(Debug)]
 enum Command {
@@ -50,49 +115,163 @@ enum Command {
     Next,
     Previous,
     Restart,
+    Seek(lib::Seek),
     TogglePlay,
     ToggleLoop,
     ToggleAutoplay,
     ToggleShowState,
+    ToggleCrossfade,
+
+    /// Change the volume by an amount.
+    ChangeVolume(f32),
+
+    /// Set the volume, from `0` to `1`.
+    SetVolume(f32),
+    ToggleMute,
+    CycleReplayGain,
+
+    /// Change the tempo by an amount.
+    ChangeTempo(f32),
+    SetTempo(f32),
+
+    /// Set the ffmpeg filter graph songs are run through.
+    SetFilter(Option<String>),
+
+    /// Switch to an equalizer preset.
+    /// `None` lists the presets.
+    SetEqPreset(Option<String>),
+    ToggleEq,
+
+    /// Show the health of playback.
+    ShowStats,
+
+    /// Switch to the output device with the given name.
+    /// `None` lists the devices.
+    SetDevice(Option<String>),
 }
 
 pub fn main() -> Result<(), ()> {
     log::enable();
 
-    let mut args = env::args();
-    let program = args.next().expect("program name");
-    let mut args = args.collect::<Vec<String>>();
-    let dir = match args.len() {
-        0 => {
-            tracing::info!("No path provided, using current location.");
-            env::current_dir().expect("can not get current directory")
-        }
-        1 => PathBuf::from(args.remove(0)),
-        _ => {
-            let mut stdout = io::stdout();
-            writeln!(stdout, "{program} use").expect("write to stdout");
-            writeln!(stdout, "{program} <path>").expect("write to stdout");
+    let args = cli::command().get_matches();
+    if let Some((cli::CMD_ANALYZE, args)) = args.subcommand() {
+        ffm::init().expect("could not initialize ffmpeg");
+        analyze::run(cli::dir(args), args.get_flag(cli::ARG_FORCE));
+        return Ok(());
+    }
+
+    let settings = match cli::config(&args).or_else(config::default_path) {
+        Some(path) => match config::Config::load(&path) {
+            Ok(settings) => settings,
+            Err(err) => {
+                tracing::error!("{err} ({path:?})");
+                return Err(());
+            }
+        },
+        None => config::Config::default(),
+    };
+
+    if let Some((cli::CMD_DEVICES, args)) = args.subcommand() {
+        let host = init_host(cli::host(args).or(settings.output.host))?;
+        device::list(&host);
+        return Ok(());
+    }
+
+    let dir = cli::dir(&args);
+    let host_id = cli::host(&args).or(settings.output.host);
+    let eq_preset = cli::eq(&args);
+    if let Some(name) = eq_preset.as_ref() {
+        if settings.eq_preset(name).is_none() {
+            tracing::error!("unknown equalizer preset `{name}`");
             return Err(());
         }
+    }
+
+    let (crossfade, crossfade_enabled) = cli::crossfade(&args);
+    let cfg = JukeboxConfig {
+        crossfade,
+        crossfade_enabled,
+        replay_gain: cli::replay_gain(&args),
+        filter: cli::filter(&args),
+        eq_presets: settings.eq_presets,
+        eq_preset,
+        host: host_id,
+        ..Default::default()
     };
 
     ffm::init().expect("could not initialize ffmpeg");
-    let (output_device, stream_config) = init_cpal();
-    let stream_builder =
-        lib::AudioStreamBuilder::new(output_device, stream_config, AUDIO_BUFFER_SIZE);
+    let playlist = create_playlist_from_dir(&dir);
+    if playlist.is_empty() {
+        tracing::info!("No audio files are present");
+        return Ok(());
+    }
 
-    run(stream_builder, dir);
-    Ok(())
+    // open the device at the rate of the first song, so it is not resampled
+    let native_rate = lib::AudioFile::from_path(playlist[0].clone())
+        .ok()
+        .and_then(|audio_file| audio_file.sample_rate());
+    let preference = cli::config_preference(&args, native_rate);
+
+    let host = init_host(host_id)?;
+    let (output_device, stream_config) =
+        init_cpal(&host, cli::device(&args).as_deref(), &preference)?;
+    let buffer = cli::buffer(&args)
+        .or(settings.output.buffer)
+        .unwrap_or(DEFAULT_AUDIO_BUFFER);
+    let mut stream_builder = lib::AudioStreamBuilder::new(output_device, stream_config, buffer)
+        .with_decode_error_policy(cli::decode_error_policy(&args));
+    if let Some(period) = cli::period(&args).or(settings.output.period) {
+        stream_builder = stream_builder.with_period_size(period);
+    }
+    if let Some(layout) = cli::channel_layout(&args) {
+        stream_builder = stream_builder.with_channel_layout(layout);
+    }
+    if let Some(fade) = cli::fade(&args) {
+        stream_builder = stream_builder.with_fade(fade);
+    }
+    if cli::bit_perfect(&args) {
+        stream_builder = stream_builder.with_bit_perfect(true);
+        if cfg.replay_gain.mode != lib::ReplayGainMode::Off
+            || cfg.filter.is_some()
+            || cfg.eq_preset.is_some()
+        {
+            tracing::warn!(
+                "ReplayGain, filters, and the equalizer change songs played bit-perfect"
+            );
+        }
+    }
+
+    let loudness = match loudness_cache_path().map(lib::LoudnessCache::load) {
+        Some(Ok(loudness)) => loudness,
+        Some(Err(err)) => {
+            tracing::warn!(?err);
+            lib::LoudnessCache::default()
+        }
+        None => lib::LoudnessCache::default(),
+    };
+
+    let cache = lib::DecodedCache::new(cli::cache_size(&args));
+    // devices switched to are opened at the rate of the song playing then
+    let preference = cli::config_preference(&args, None);
+    run(stream_builder, playlist, preference, loudness, cache, cfg)
 }
 
 /// # Arguments
-/// + `dir`: Path to directory containing sound files.
-fn run(stream_builder: lib::AudioStreamBuilder, dir: impl AsRef<Path>) {
-    let playlist = create_playlist_from_dir(dir.as_ref());
-    if playlist.is_empty() {
-        tracing::info!("No audio files are present");
-        return;
-    }
+/// + `playlist`: Songs to play.
+/// + `preference`: Config to open devices switched to with.
+/// + `loudness`: Analyzed loudness of songs.
+/// + `cache`: Cache for decoded songs.
+///
+/// # Returns
+/// + `Err` if the player could not start, e.g. the output could not be built.
+fn run(
+    stream_builder: lib::AudioStreamBuilder,
+    playlist: lib::Playlist,
+    preference: device::ConfigPreference,
+    loudness: lib::LoudnessCache,
+    cache: lib::DecodedCache,
+    cfg: JukeboxConfig,
+) -> Result<(), ()> {
     let queue = lib::PlaylistQueue::new(playlist);
 
     let (input_tx, input_rx) = channel::bounded(1);
@@ -104,18 +283,60 @@ fn run(stream_builder: lib::AudioStreamBuilder, dir: impl AsRef<Path>) {
 
     let (command_tx, command_rx) = channel::bounded(1);
     let (event_tx, event_rx) = channel::bounded(1);
+    let (ready_tx, ready_rx) = channel::bounded(1);
+    let host_id = cfg.host;
     let _t_player = std::thread::Builder::new()
         .name("player actor".to_string())
         .spawn(move || {
-            let mut player =
-                player_actor::AudioPlayerActor::new(stream_builder, command_rx, event_tx);
+            // devices are found on the host when reconnecting or switching
+            let host = match device::host(host_id) {
+                Ok(host) => host,
+                Err(err) => {
+                    let _ = ready_tx.send(Err(error::Startup::Host(err)));
+                    return;
+                }
+            };
+
+            // output is `!Send` so must be created on the player's thread
+            let output = match stream_builder.build_output() {
+                Ok(output) => output,
+                Err(err) => {
+                    let _ = ready_tx.send(Err(error::Startup::Output(err)));
+                    return;
+                }
+            };
+            let _ = ready_tx.send(Ok(()));
+
+            let mut player = player_actor::AudioPlayerActor::new(
+                stream_builder,
+                host,
+                preference,
+                output,
+                loudness,
+                cache,
+                command_rx,
+                event_tx,
+            );
 
             player.run();
         })
         .expect("could not launch player actor");
 
-    let mut jukebox = JukeBox::new(queue, input_rx, command_tx, event_rx);
-    jukebox.run()
+    match ready_rx.recv() {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => {
+            tracing::error!("{err}");
+            return Err(());
+        }
+        Err(_) => {
+            tracing::error!("player actor exited before starting");
+            return Err(());
+        }
+    }
+
+    let mut jukebox = JukeBox::new(queue, input_rx, command_tx, event_rx, cfg);
+    jukebox.run();
+    Ok(())
 }
 
 struct JukeboxConfig {
@@ -129,6 +350,35 @@ struct JukeboxConfig {
     /// i.e. `0` will only show the current song,
     /// `1` will show the the previous, current, and next song.
     playlist_buffer: usize,
+
+    /// How to fade between songs.
+    crossfade: lib::CrossfadeConfig,
+
+    /// Fade between songs, rather than playing them back to back.
+    crossfade_enabled: bool,
+
+    /// Volume, from `0` (silent) to `1` (full).
+    volume: f32,
+    muted: bool,
+
+    /// How to normalize the loudness of songs.
+    replay_gain: lib::ReplayGainConfig,
+
+    /// Speed songs are played at.
+    tempo: f32,
+
+    /// ffmpeg filter graph songs are run through.
+    filter: Option<String>,
+
+    /// Equalizer presets from the settings file.
+    eq_presets: Vec<config::EqPreset>,
+
+    /// Name of the equalizer preset songs are equalized with.
+    eq_preset: Option<String>,
+    eq_bypass: bool,
+
+    /// Audio host devices are found through, the default host if `None`.
+    host: Option<cpal::HostId>,
 }
 
 impl Default for JukeboxConfig {
@@ -137,6 +387,21 @@ impl Default for JukeboxConfig {
             autoplay: true,
             playlist_buffer: 1,
             show_state: true,
+            crossfade: lib::CrossfadeConfig {
+                duration: DEFAULT_CROSSFADE,
+                curve: lib::FadeCurve::EqualPower,
+                on_skip: true,
+            },
+            crossfade_enabled: false,
+            volume: 1.0,
+            muted: false,
+            replay_gain: lib::ReplayGainConfig::default(),
+            tempo: 1.0,
+            filter: None,
+            eq_presets: Vec::new(),
+            eq_preset: None,
+            eq_bypass: false,
+            host: None,
         }
     }
 }
@@ -146,8 +411,13 @@ struct JukeBox {
     input_rx: channel::Receiver<Command>,
     command_tx: channel::Sender<player_actor::Command>,
     event_rx: channel::Receiver<player_actor::Event>,
-    stream_state: Option<lib::StreamStateLock>,
+    progress_rx: channel::Receiver<std::time::Instant>,
+    stream: Option<player_actor::StreamHandle>,
     cfg: JukeboxConfig,
+
+    /// Songs skipped in a row as they could not be played.
+    /// Playback stops once the whole playlist has been skipped.
+    skipped: usize,
 }
 
 impl JukeBox {
@@ -156,18 +426,42 @@ impl JukeBox {
         input_rx: channel::Receiver<Command>,
         command_tx: channel::Sender<player_actor::Command>,
         event_rx: channel::Receiver<player_actor::Event>,
+        cfg: JukeboxConfig,
     ) -> Self {
         Self {
             queue,
             input_rx,
             command_tx,
             event_rx,
-            stream_state: None,
-            cfg: JukeboxConfig::default(),
+            progress_rx: channel::tick(PROGRESS_INTERVAL),
+            stream: None,
+            cfg,
+            skipped: 0,
         }
     }
 
     fn run(&mut self) {
+        self.set_crossfade()
+            .map_err(|_| ())
+            .expect("could not set crossfade");
+
+        self.set_volume()
+            .map_err(|_| ())
+            .expect("could not set volume");
+
+        self.set_replay_gain()
+            .map_err(|_| ())
+            .expect("could not set replay gain");
+
+        self.set_equalizer()
+            .map_err(|_| ())
+            .expect("could not set equalizer");
+
+        if let Err(err) = self.set_filter(self.cfg.filter.clone()) {
+            tracing::error!(?err);
+            return;
+        }
+
         self.prepare_current_song()
             .map_err(|_| ())
             .expect("could not play song");
@@ -213,11 +507,17 @@ impl JukeBox {
                         break;
                     }
                 },
+
+                recv(self.progress_rx) -> _ => self.show_progress(),
             }
         }
     }
 
     fn handle_command(&mut self, cmd: Command) -> Result<(), ()> {
+        if matches!(cmd, Command::Next | Command::Previous | Command::Restart) {
+            self.skipped = 0;
+        }
+
         match cmd {
             Command::Next => {
                 self.prepare_next_song().map_err(|_| ())?;
@@ -228,32 +528,127 @@ impl JukeBox {
                 self.play();
             }
             Command::Restart => {
-                // TODO: Currently unloads and reloads the audio file.
-                // Should be able to seek and restart without unloading.
-                let state = self
-                    .stream_state
-                    .as_ref()
-                    .map(|state_lock| *state_lock.lock().unwrap());
-
-                self.prepare_current_song().map_err(|_| ())?;
-                if matches!(state, Some(lib::StreamState::Play)) {
-                    self.play();
+                let state = self.stream.as_ref().map(|stream| stream.control.state());
+
+                if matches!(state, None | Some(lib::StreamState::Done)) {
+                    // stream finished loading, so can not be seeked,
+                    // its audio still queued is cut off as a seek would
+                    self.command_tx
+                        .send(player_actor::Command::Flush)
+                        .map_err(|_| ())?;
+                    self.prepare_current_song().map_err(|_| ())?;
+                } else {
+                    self.seek(lib::Seek::To(Duration::ZERO));
                 }
             }
+            Command::Seek(seek) => {
+                self.seek(seek);
+            }
             Command::TogglePlay => {
                 self.toggle_play().map_err(|_| ())?;
             }
             Command::ToggleLoop => {
                 self.queue.set_looping(!self.queue.is_looping());
+                self.preload_next_song().map_err(|_| ())?;
             }
             Command::ToggleAutoplay => {
                 self.cfg.autoplay = !self.cfg.autoplay;
                 tracing::info!("autoplay {:?}", self.cfg.autoplay);
+                self.preload_next_song().map_err(|_| ())?;
             }
             Command::ToggleShowState => {
                 self.cfg.show_state = !self.cfg.show_state;
                 tracing::info!("show state {:?}", self.cfg.show_state);
             }
+            Command::ToggleCrossfade => {
+                self.cfg.crossfade_enabled = !self.cfg.crossfade_enabled;
+                tracing::info!("crossfade {:?}", self.cfg.crossfade_enabled);
+                self.set_crossfade().map_err(|_| ())?;
+            }
+            Command::ChangeVolume(change) => {
+                self.cfg.volume = (self.cfg.volume + change).clamp(0.0, 1.0);
+                self.cfg.muted = false;
+                self.set_volume().map_err(|_| ())?;
+                self.show_state();
+            }
+            Command::SetVolume(volume) => {
+                self.cfg.volume = volume.clamp(0.0, 1.0);
+                self.cfg.muted = false;
+                self.set_volume().map_err(|_| ())?;
+                self.show_state();
+            }
+            Command::ToggleMute => {
+                self.cfg.muted = !self.cfg.muted;
+                tracing::info!("muted {:?}", self.cfg.muted);
+                self.set_volume().map_err(|_| ())?;
+                self.show_state();
+            }
+            Command::CycleReplayGain => {
+                self.cfg.replay_gain.mode = match self.cfg.replay_gain.mode {
+                    lib::ReplayGainMode::Off => lib::ReplayGainMode::Track,
+                    lib::ReplayGainMode::Track => lib::ReplayGainMode::Album,
+                    lib::ReplayGainMode::Album => lib::ReplayGainMode::Off,
+                };
+                tracing::info!("replay gain {:?}", self.cfg.replay_gain.mode);
+                self.set_replay_gain().map_err(|_| ())?;
+                self.show_state();
+            }
+            Command::ChangeTempo(change) => {
+                // round off float error of repeated steps
+                let tempo = ((self.cfg.tempo + change) * 100.0).round() / 100.0;
+                match self.set_tempo(tempo) {
+                    Ok(()) => self.show_state(),
+                    Err(error::Player::Stream(err)) => {
+                        // keep playing at the previous tempo
+                        tracing::warn!(?err);
+                    }
+                    Err(_) => return Err(()),
+                }
+            }
+            Command::SetTempo(tempo) => match self.set_tempo(tempo) {
+                Ok(()) => self.show_state(),
+                Err(error::Player::Stream(err)) => {
+                    // keep playing at the previous tempo
+                    tracing::warn!(?err);
+                }
+                Err(_) => return Err(()),
+            },
+            Command::SetFilter(filter) => match self.set_filter(filter) {
+                Ok(()) => self.show_state(),
+                Err(error::Player::Stream(err)) => {
+                    // keep playing with the previous filter
+                    tracing::warn!(?err);
+                }
+                Err(_) => return Err(()),
+            },
+            Command::SetEqPreset(Some(name)) => {
+                if self.cfg.eq_presets.iter().any(|preset| preset.name == name) {
+                    tracing::info!("equalizer {name:?}");
+                    self.cfg.eq_preset = Some(name);
+                    self.cfg.eq_bypass = false;
+                    self.set_equalizer().map_err(|_| ())?;
+                    self.show_state();
+                } else {
+                    tracing::warn!("unknown equalizer preset `{name}`");
+                }
+            }
+            Command::SetEqPreset(None) => self.show_eq_presets(),
+            Command::ToggleEq => {
+                self.cfg.eq_bypass = !self.cfg.eq_bypass;
+                tracing::info!("equalizer bypass {:?}", self.cfg.eq_bypass);
+                self.set_equalizer().map_err(|_| ())?;
+                self.show_state();
+            }
+            Command::ShowStats => self.show_stats().map_err(|_| ())?,
+            Command::SetDevice(Some(name)) => self.set_device(name).map_err(|_| ())?,
+            Command::SetDevice(None) => match device::host(self.cfg.host) {
+                Ok(host) => {
+                    let mut stdout = io::stdout();
+                    write_trace!(stdout, "\r\x1B[2K"); // clear progress line
+                    device::list(&host);
+                }
+                Err(err) => tracing::error!(?err),
+            },
             Command::Quit => unreachable!("handled elsewhere"),
         }
 
@@ -263,6 +658,7 @@ impl JukeBox {
     fn handle_event(&mut self, event: player_actor::Event) -> Result<(), error::Player> {
         match event {
             player_actor::Event::Done => {
+                self.skipped = 0;
                 if self.cfg.autoplay {
                     self.prepare_next_song()?;
                     self.play();
@@ -272,6 +668,34 @@ impl JukeBox {
                     Ok(())
                 }
             }
+            player_actor::Event::Advanced(stream) => {
+                self.skipped = 0;
+                self.queue.next();
+                let _ = self.stream.insert(stream);
+                self.show_state();
+                self.preload_next_song()
+            }
+            player_actor::Event::Abandoned(err) => {
+                // move on to the next song rather than stopping the player
+                tracing::warn!("Skipping song that could not be decoded");
+                tracing::debug!(?err);
+                if !self.skip_song() {
+                    return Ok(());
+                }
+
+                self.prepare_next_song()?;
+                self.play();
+                Ok(())
+            }
+            player_actor::Event::Reconnected(stream) => {
+                let _ = self.stream.insert(stream);
+                self.show_state();
+                Ok(())
+            }
+            player_actor::Event::Warning(err) => {
+                tracing::warn!(?err);
+                Ok(())
+            }
             player_actor::Event::StreamErr(err) => {
                 tracing::error!(?err);
                 Err(error::Player::Stream(err))
@@ -281,7 +705,7 @@ impl JukeBox {
 
     fn prepare_current_song(&mut self) -> Result<(), error::Player> {
         if let Some(file) = self.queue.current().cloned() {
-            self.load_and_prepare_stream(file.clone())
+            self.load_song(file)
         } else {
             self.pause();
             tracing::info!("End of playlist");
@@ -291,7 +715,7 @@ impl JukeBox {
 
     fn prepare_next_song(&mut self) -> Result<(), error::Player> {
         if let Some(file) = self.queue.next().cloned() {
-            self.load_and_prepare_stream(file.clone())
+            self.load_song(file)
         } else {
             self.pause();
             tracing::info!("End of playlist");
@@ -301,7 +725,7 @@ impl JukeBox {
 
     fn play_previous_song(&mut self) -> Result<(), error::Player> {
         if let Some(file) = self.queue.next_back().cloned() {
-            self.load_and_prepare_stream(file)
+            self.load_song(file)
         } else {
             self.pause();
             tracing::info!("End of playlist");
@@ -309,13 +733,60 @@ impl JukeBox {
         }
     }
 
+    /// Counts a song skipped as it could not be played.
+    ///
+    /// # Returns
+    /// `false` if the whole playlist has been skipped, so playback is stopped.
+    fn skip_song(&mut self) -> bool {
+        self.skipped += 1;
+        if self.skipped < self.queue.len() {
+            return true;
+        }
+
+        self.skipped = 0;
+        self.pause();
+        tracing::error!("No song in the playlist can be played");
+        false
+    }
+
+    /// Loads a song, moving on to the songs after it while they can not be loaded.
+    ///
+    /// # Returns
+    /// + `Err` if the command channel closed.
+    fn load_song(&mut self, mut file: PathBuf) -> Result<(), error::Player> {
+        loop {
+            match self.load_and_prepare_stream(file) {
+                Ok(()) => return Ok(()),
+                Err(err @ (error::Player::Load(_) | error::Player::Stream(_))) => {
+                    tracing::warn!("Skipping song that could not be loaded");
+                    tracing::debug!(?err);
+                }
+                Err(err) => return Err(err),
+            }
+
+            if !self.skip_song() {
+                return Ok(());
+            }
+
+            match self.queue.next().cloned() {
+                Some(next) => file = next,
+                None => {
+                    self.pause();
+                    tracing::info!("End of playlist");
+                    return Ok(());
+                }
+            }
+        }
+    }
+
     /// Loads a new song to the player actor and begins playing it.
     ///
     /// # Returns
+    /// + `Err(Load | Stream)` if the song could not be loaded.
     /// + `Err` if the command channel closed.
     fn load_and_prepare_stream(&mut self, file: PathBuf) -> Result<(), error::Player> {
-        if let Some(state_lock) = self.stream_state.as_ref() {
-            *state_lock.lock().unwrap() = lib::StreamState::Stop;
+        if let Some(stream) = self.stream.as_ref() {
+            stream.control.stop();
         };
 
         let (res_tx, res_rx) = channel::bounded(1);
@@ -323,7 +794,7 @@ impl JukeBox {
             .send(player_actor::Command::Load(file.clone(), res_tx))?;
 
         if let Err(err) = res_rx.recv()? {
-            tracing::error!(?err);
+            tracing::debug!(?err);
             return Err(err.into());
         }
         tracing::trace!("{file:?} loaded");
@@ -333,9 +804,9 @@ impl JukeBox {
             .send(player_actor::Command::Prepare(res_tx))?;
 
         match res_rx.recv()? {
-            Ok(stream_state) => {
-                tracing::debug!("{:?}", stream_state.lock().unwrap());
-                let _ = self.stream_state.insert(stream_state);
+            Ok(stream) => {
+                tracing::debug!("{:?}", stream.control.state());
+                let _ = self.stream.insert(stream);
             }
             Err(err) => {
                 tracing::error!(?err);
@@ -343,8 +814,40 @@ impl JukeBox {
             }
         }
 
+        self.show_state();
+        self.preload_next_song()
+    }
+
+    /// Opens the next song in the player so it can follow the current one without a gap.
+    /// Clears the preloaded song if nothing should follow automatically.
+    ///
+    /// # Returns
+    /// + `Err` if the command channel closed.
+    fn preload_next_song(&mut self) -> Result<(), error::Player> {
+        let file = if self.cfg.autoplay {
+            self.queue.peek_next().cloned()
+        } else {
+            None
+        };
+
+        let (res_tx, res_rx) = channel::bounded(1);
+        self.command_tx
+            .send(player_actor::Command::Preload(file.clone(), res_tx))?;
+
+        if let Err(err) = res_rx.recv()? {
+            // song will be loaded again once the current one is done
+            tracing::warn!(?err);
+        } else if let Some(file) = file {
+            tracing::trace!("{file:?} preloaded");
+        }
+
+        Ok(())
+    }
+
+    fn show_state(&self) {
         if self.cfg.show_state {
             let mut stdout = io::stdout();
+            write_trace!(stdout, "\r\x1B[2K"); // clear progress line
             let playlist = self.queue.playlist();
             let index = self.queue.index();
             let idx_start = index.checked_sub(self.cfg.playlist_buffer).unwrap_or(0);
@@ -377,43 +880,265 @@ impl JukeBox {
             write_trace!(stdout, "\n");
             write_trace!(
                 stdout,
-                "looping: {:?}, autoplay: {:?}\n",
+                "looping: {:?}, autoplay: {:?}",
                 self.queue.is_looping(),
                 self.cfg.autoplay,
             );
+            if self.cfg.crossfade_enabled {
+                write_trace!(stdout, ", crossfade: {:?}", self.cfg.crossfade.duration);
+            }
+            if self.cfg.muted {
+                write_trace!(stdout, ", volume: muted");
+            } else {
+                write_trace!(stdout, ", volume: {:.0}%", self.cfg.volume * 100.0);
+            }
+            if self.cfg.replay_gain.mode != lib::ReplayGainMode::Off {
+                write_trace!(stdout, ", replay gain: {:?}", self.cfg.replay_gain.mode);
+            }
+            if self.cfg.tempo != 1.0 {
+                write_trace!(stdout, ", tempo: {}x", self.cfg.tempo);
+            }
+            if let Some(filter) = self.cfg.filter.as_ref() {
+                write_trace!(stdout, ", filter: {filter}");
+            }
+            if let Some(preset) = self.cfg.eq_preset.as_ref() {
+                if self.cfg.eq_bypass {
+                    write_trace!(stdout, ", eq: {preset} (bypassed)");
+                } else {
+                    write_trace!(stdout, ", eq: {preset}");
+                }
+            }
+            write_trace!(stdout, "\n");
+        }
+    }
+
+    /// # Returns
+    /// + `Err` if the command channel closed.
+    fn set_crossfade(&mut self) -> Result<(), error::Player> {
+        let crossfade = if self.cfg.crossfade_enabled {
+            Some(self.cfg.crossfade)
+        } else {
+            None
+        };
+
+        self.command_tx
+            .send(player_actor::Command::SetCrossfade(crossfade))?;
+        Ok(())
+    }
+
+    /// # Returns
+    /// + `Err` if the command channel closed.
+    fn set_volume(&mut self) -> Result<(), error::Player> {
+        let volume = if self.cfg.muted { 0.0 } else { self.cfg.volume };
+        self.command_tx
+            .send(player_actor::Command::SetVolume(volume))?;
+        Ok(())
+    }
+
+    /// # Returns
+    /// + `Err` if the command channel closed.
+    fn set_replay_gain(&mut self) -> Result<(), error::Player> {
+        self.command_tx
+            .send(player_actor::Command::SetReplayGain(self.cfg.replay_gain))?;
+        Ok(())
+    }
+
+    /// # Returns
+    /// + `Err(Stream)` if the tempo of the songs could not be changed.
+    /// + `Err` if the command channel closed.
+    fn set_tempo(&mut self, tempo: f32) -> Result<(), error::Player> {
+        let tempo = tempo.clamp(*lib::TEMPO_RANGE.start(), *lib::TEMPO_RANGE.end());
+        let (res_tx, res_rx) = channel::bounded(1);
+        self.command_tx
+            .send(player_actor::Command::SetTempo(tempo, res_tx))?;
+
+        res_rx.recv()?.map_err(error::Player::Stream)?;
+        tracing::info!("tempo {tempo:?}");
+        self.cfg.tempo = tempo;
+        Ok(())
+    }
+
+    /// # Returns
+    /// + `Err(Stream)` if the filter is invalid.
+    /// + `Err` if the command channel closed.
+    fn set_filter(&mut self, filter: Option<String>) -> Result<(), error::Player> {
+        let (res_tx, res_rx) = channel::bounded(1);
+        self.command_tx
+            .send(player_actor::Command::SetFilter(filter.clone(), res_tx))?;
+
+        res_rx.recv()?.map_err(error::Player::Stream)?;
+        tracing::info!("filter {filter:?}");
+        self.cfg.filter = filter;
+        Ok(())
+    }
+
+    /// # Returns
+    /// + `Err` if the command channel closed.
+    fn set_equalizer(&mut self) -> Result<(), error::Player> {
+        let preset = self.cfg.eq_preset.as_ref().and_then(|name| {
+            self.cfg
+                .eq_presets
+                .iter()
+                .find(|preset| &preset.name == name)
+        });
+        let bands = preset
+            .filter(|_| !self.cfg.eq_bypass)
+            .map(|preset| preset.bands.clone());
+
+        self.command_tx
+            .send(player_actor::Command::SetEqualizer(bands))?;
+        Ok(())
+    }
+
+    fn show_eq_presets(&self) {
+        let mut stdout = io::stdout();
+        write_trace!(stdout, "\r\x1B[2K"); // clear progress line
+        if self.cfg.eq_presets.is_empty() {
+            write_trace!(stdout, "no equalizer presets\n");
+        }
+        for preset in self.cfg.eq_presets.iter() {
+            if self.cfg.eq_preset.as_ref() == Some(&preset.name) {
+                write_trace!(stdout, "* {}\n", preset.name);
+            } else {
+                write_trace!(stdout, "  {}\n", preset.name);
+            }
+        }
+    }
+
+    /// Moves playback to another output device, at the same position in the song.
+    ///
+    /// # Returns
+    /// + `Err` if the command channel closed.
+    fn set_device(&mut self, name: String) -> Result<(), error::Player> {
+        let (res_tx, res_rx) = channel::bounded(1);
+        self.command_tx
+            .send(player_actor::Command::SetDevice(name.clone(), res_tx))?;
+
+        match res_rx.recv()? {
+            Ok(()) => tracing::info!("playing through `{name}`"),
+            Err(err) => {
+                tracing::debug!(?err);
+                tracing::warn!("could not switch to output device `{name}`");
+            }
         }
 
         Ok(())
     }
 
+    /// # Returns
+    /// + `Err` if the command channel closed.
+    fn show_stats(&self) -> Result<(), error::Player> {
+        let (res_tx, res_rx) = channel::bounded(1);
+        self.command_tx.send(player_actor::Command::Stats(res_tx))?;
+
+        let stats = res_rx.recv()?;
+        tracing::debug!(?stats);
+
+        let mut stdout = io::stdout();
+        write_trace!(stdout, "\r\x1B[2K"); // clear progress line
+        write_trace!(
+            stdout,
+            "underruns: {} ({:?} of silence)\n",
+            stats.underruns,
+            stats.underrun_silence
+        );
+        for underrun in stats.recent_underruns.iter() {
+            write_trace!(
+                stdout,
+                "  {} x{} ({:?})\n",
+                format_time(underrun.time),
+                underrun.count,
+                underrun.silence
+            );
+        }
+        write_trace!(
+            stdout,
+            "buffer: {:.0}% ({}ms of {}ms)\n",
+            stats.buffer_fill() * 100.0,
+            stats.buffered.as_millis(),
+            stats.buffer_capacity.as_millis()
+        );
+        match (stats.recent_decode_speed, stats.decode_speed) {
+            (Some(recent), Some(total)) => {
+                write_trace!(stdout, "decode speed: {recent:.1}x (overall {total:.1}x)\n")
+            }
+            _ => write_trace!(stdout, "decode speed: --\n"),
+        }
+        for (kind, allocations) in [
+            ("sample buffers", stats.allocations.sample_buffers),
+            ("frames", stats.allocations.frames),
+            ("ring buffers", stats.allocations.ring_buffers),
+        ] {
+            write_trace!(
+                stdout,
+                "{kind}: {} allocated, {} reused, {} pooled\n",
+                allocations.allocated,
+                allocations.reused,
+                allocations.pooled
+            );
+        }
+        write_trace!(stdout, "uptime: {}\n", format_time(stats.uptime));
+        Ok(())
+    }
+
+    /// Redraws the progress line of the current song.
+    fn show_progress(&self) {
+        if !self.cfg.show_state {
+            return;
+        }
+        let Some(stream) = self.stream.as_ref() else {
+            return;
+        };
+
+        let mut stdout = io::stdout();
+        write_trace!(
+            stdout,
+            "\r\x1B[2K{} / {}",
+            format_time(stream.clock.position()),
+            stream
+                .clock
+                .duration()
+                .map(format_time)
+                .unwrap_or_else(|| "--:--".to_string()),
+        );
+        if let Some(remaining) = stream.clock.remaining() {
+            write_trace!(stdout, " (-{})", format_time(remaining));
+        }
+        if let Err(err) = stdout.flush() {
+            tracing::error!(?err);
+        }
+    }
+
     fn play(&mut self) {
-        if let Some(state_lock) = self.stream_state.as_ref() {
-            let mut state = state_lock.lock().unwrap();
-            *state = lib::StreamState::Play;
+        if let Some(stream) = self.stream.as_ref() {
+            stream.control.play();
             tracing::info!("Playing");
         }
     }
 
     fn pause(&mut self) {
-        if let Some(state_lock) = self.stream_state.as_ref() {
-            let mut state = state_lock.lock().unwrap();
-            *state = lib::StreamState::Pause;
+        if let Some(stream) = self.stream.as_ref() {
+            stream.control.pause();
             tracing::info!("Paused");
         }
     }
 
+    fn seek(&mut self, seek: lib::Seek) {
+        if let Some(stream) = self.stream.as_ref() {
+            stream.control.seek(seek);
+            tracing::info!("Seeking {seek:?}");
+        }
+    }
+
     fn toggle_play(&mut self) -> Result<(), channel::SendError<player_actor::Command>> {
-        let Some(state_lock) = self.stream_state.as_ref() else {
+        let Some(stream) = self.stream.as_ref() else {
             return Ok(());
         };
 
-        let mut state = state_lock.lock().unwrap();
-        if state.is_playing() {
-            *state = lib::StreamState::Pause;
-            tracing::info!("Paused");
-        } else {
-            *state = lib::StreamState::Play;
+        if stream.control.toggle_play().is_playing() {
             tracing::info!("Playing");
+        } else {
+            tracing::info!("Paused");
         }
 
         Ok(())
@@ -447,21 +1172,85 @@ fn create_playlist_from_dir(dir: impl AsRef<Path>) -> lib::Playlist {
     lib::Playlist::new(audio_files)
 }
 
-fn init_cpal() -> (cpal::Device, cpal::SupportedStreamConfig) {
-    let device = cpal::default_host()
-        .default_output_device()
-        .expect("no output device available");
+/// File the analyzed loudness of songs is kept in.
+fn loudness_cache_path() -> Option<PathBuf> {
+    let cache_dir = env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+
+    Some(cache_dir.join(env!("CARGO_PKG_NAME")).join("loudness.tsv"))
+}
+
+/// Formats a time as `mm:ss`, or `h:mm:ss` if over an hour.
+fn format_time(time: Duration) -> String {
+    let secs = time.as_secs();
+    let (hours, mins, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{hours}:{mins:02}:{secs:02}")
+    } else {
+        format!("{mins:02}:{secs:02}")
+    }
+}
+
+/// # Arguments
+/// + `id`: Audio host to use, the default host if `None`.
+///
+/// # Returns
+/// + `Err` if the host can not be used.
+fn init_host(id: Option<cpal::HostId>) -> Result<cpal::Host, ()> {
+    device::host(id).map_err(|err| {
+        let name = id.map_or("default", |id| id.name());
+        tracing::error!("could not use the {name} audio host: {err}");
+    })
+}
+
+/// # Arguments
+/// + `device_name`: Name of the output device to play through, the default device if `None`.
+/// + `preference`: Config to open the device with.
+///
+/// # Returns
+/// + `Err` if there is no device with the given name,
+///   or it has no config with the required values.
+fn init_cpal(
+    host: &cpal::Host,
+    device_name: Option<&str>,
+    preference: &device::ConfigPreference,
+) -> Result<(cpal::Device, cpal::SupportedStreamConfig), ()> {
+    let device = match device_name {
+        Some(name) => match device::find_output(host, name) {
+            Some(device) => device,
+            None => {
+                tracing::error!(
+                    "no output device named `{name}`, see `{}`",
+                    cli::CMD_DEVICES
+                );
+                return Err(());
+            }
+        },
+        None => match host.default_output_device() {
+            Some(device) => device,
+            None => {
+                tracing::error!(
+                    "no default output device, pick one with `--{}`, see `{}`",
+                    cli::ARG_DEVICE,
+                    cli::CMD_DEVICES
+                );
+                return Err(());
+            }
+        },
+    };
 
     // Create an output stream for the audio so we can play it
-    // NOTE: If system doesn't support the file's sample rate, the program will panic when we try to play,
-    //       so we'll need to resample the audio to a supported config
-    let supported_config_range = device
-        .supported_output_configs()
-        .expect("error querying audio output configs")
-        .next()
-        .expect("no supported audio config found");
-
-    (device, supported_config_range.with_max_sample_rate())
+    let Some(stream_config) = device::stream_config(&device, preference) else {
+        tracing::error!(
+            "no supported audio config found with the given sample rate, channels, and format, see `{}`",
+            cli::CMD_DEVICES
+        );
+        return Err(());
+    };
+
+    tracing::debug!(?stream_config);
+    Ok((device, stream_config))
 }
 
 mod error {
@@ -485,6 +1274,16 @@ mod error {
         Stream(lib::error::AudioStream),
     }
 
+    /// Why the player could not start.
+    #[derive(thiserror::Error, Debug)]
+    pub enum Startup {
+        #[error("could not use the audio host: {0}")]
+        Host(cpal::HostUnavailable),
+
+        #[error("could not open the output device: {0:?}")]
+        Output(lib::error::AudioOutput),
+    }
+
     impl<T> From<channel::SendError<T>> for Player {
         fn from(_: channel::SendError<T>) -> Self {
             Self::Channel
@@ -504,6 +1303,7 @@ mod error {
             match value {
                 Load::Audio(err) => Self::Load(err),
                 Load::Stream(err) => Self::Load(err),
+                Load::Filter(err) => Self::Stream(err),
             }
         }
     }
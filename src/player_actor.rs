@@ -1,70 +1,308 @@
-use crossbeam::channel;
+use crate::device;
+use cpal::traits::*;
+use crossbeam::{channel, select};
 use sensit_audio_cli as lib;
-use std::path::PathBuf;
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// How often to look for an output device while there is none to play through.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Debug)]
 pub enum Event {
     /// Current stream errored.
     StreamErr(lib::error::AudioStream),
 
+    /// An operation on the current stream failed, e.g. a seek,
+    /// but it keeps playing as it was.
+    Warning(lib::error::AudioStream),
+
+    /// Current song could not be decoded, so was abandoned.
+    Abandoned(lib::error::AudioStream),
+
     /// Current playing song has finished.
     Done,
+
+    /// Current song has finished and the preloaded song started playing in its place.
+    Advanced(StreamHandle),
+
+    /// Playback moved to a new output, after the device was lost or switched.
+    /// The current song was loaded again, at the same position.
+    Reconnected(StreamHandle),
 }
 
 pub type LoadResponse = Result<(), error::Load>;
-pub type PrepareResponse = Result<lib::StreamStateLock, error::Play>;
+pub type PrepareResponse = Result<StreamHandle, error::Play>;
+pub type TempoResponse = Result<(), lib::error::AudioStream>;
+pub type FilterResponse = Result<(), lib::error::AudioStream>;
+pub type DeviceResponse = Result<(), error::Device>;
+
+/// Handles to control and observe a prepared stream.
+#[derive(Debug)]
+pub struct StreamHandle {
+    pub control: lib::StreamControl,
+    pub clock: Arc<lib::StreamClock>,
+}
+
+impl From<&lib::AudioStream> for StreamHandle {
+    fn from(stream: &lib::AudioStream) -> Self {
+        Self {
+            control: stream.control(),
+            clock: stream.clock(),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum Command {
     Load(PathBuf, channel::Sender<LoadResponse>),
+
+    /// Open a song to play once the current one finishes, without a gap.
+    /// `None` clears the preloaded song.
+    Preload(Option<PathBuf>, channel::Sender<LoadResponse>),
     Prepare(channel::Sender<PrepareResponse>),
+
+    /// Cut off the audio queued in the output, and any fade in progress,
+    /// e.g. before loading again a song that finished loading.
+    Flush,
+
+    /// Set how to fade between songs.
+    /// `None` disables crossfading.
+    SetCrossfade(Option<lib::CrossfadeConfig>),
+
+    /// Set the volume of the output, from `0` (silent) to `1` (full).
+    SetVolume(f32),
+
+    /// Set how to normalize the loudness of songs.
+    SetReplayGain(lib::ReplayGainConfig),
+
+    /// Set the speed songs are played at.
+    /// Songs keep their tempo if it can not be changed.
+    SetTempo(f32, channel::Sender<TempoResponse>),
+
+    /// Set the ffmpeg filter graph songs are run through.
+    /// `None` removes the filter.
+    SetFilter(Option<String>, channel::Sender<FilterResponse>),
+
+    /// Set the bands of the equalizer songs are run through.
+    /// `None` bypasses the equalizer.
+    SetEqualizer(Option<Vec<lib::EqBand>>),
+
+    /// Get the health of the output's playback.
+    Stats(channel::Sender<lib::PlaybackStats>),
+
+    /// Play through the output device with the given name.
+    SetDevice(String, channel::Sender<DeviceResponse>),
 }
 
 pub struct AudioPlayerActor {
     builder: lib::AudioStreamBuilder,
+
+    /// Audio host devices are found through.
+    host: cpal::Host,
+
+    /// Config to open devices switched to with.
+    /// The native rate is taken from the song playing.
+    preference: device::ConfigPreference,
+    output: lib::AudioOutput,
     command_rx: channel::Receiver<Command>,
     event_tx: channel::Sender<Event>,
     stream: Option<lib::AudioStream>,
+    next: Option<lib::AudioStream>,
+    crossfade: Option<lib::CrossfadeConfig>,
+    replay_gain: lib::ReplayGainConfig,
+    tempo: f32,
+    filter: Option<String>,
+    equalizer: Option<Vec<lib::EqBand>>,
+
+    /// Analyzed loudness of songs, used if they have no ReplayGain tags.
+    loudness: lib::LoudnessCache,
+
+    /// Recently decoded songs, played without decoding them again.
+    cache: lib::DecodedCache,
+
+    /// Fade out of the previous stream in progress.
+    fade: Option<lib::Crossfade>,
+
+    /// Sample count of the output at which the finished stream's audio ends.
+    /// [`Event::Done`] is sent once it has been played.
+    ending: Option<u64>,
+
+    /// The output's device went away, so nothing is played until another output is built.
+    lost: Option<LostDevice>,
+}
+
+/// Output device that went away.
+struct LostDevice {
+    /// Name of the device, looked for before falling back to the default device.
+    name: Option<String>,
+
+    /// When to next look for a device.
+    retry_at: Instant,
 }
 
 impl AudioPlayerActor {
     pub fn new(
         builder: lib::AudioStreamBuilder,
+        host: cpal::Host,
+        preference: device::ConfigPreference,
+        output: lib::AudioOutput,
+        loudness: lib::LoudnessCache,
+        cache: lib::DecodedCache,
         command_rx: channel::Receiver<Command>,
         event_tx: channel::Sender<Event>,
     ) -> Self {
         Self {
             builder,
+            host,
+            preference,
+            output,
+            loudness,
+            cache,
             command_rx,
             event_tx,
             stream: None,
+            next: None,
+            crossfade: None,
+            replay_gain: lib::ReplayGainConfig::default(),
+            tempo: 1.0,
+            filter: None,
+            equalizer: None,
+            fade: None,
+            ending: None,
+            lost: None,
         }
     }
 
     pub fn run(&mut self) {
         loop {
-            if let Ok(cmd) = self.command_rx.recv() {
+            // only block waiting for commands if there is no audio to load
+            let cmd = if self.is_playing() && self.lost.is_none() {
+                match self.command_rx.try_recv() {
+                    Ok(cmd) => Some(cmd),
+                    Err(channel::TryRecvError::Empty) => None,
+                    Err(channel::TryRecvError::Disconnected) => {
+                        tracing::error!("command channel closed");
+                        break;
+                    }
+                }
+            } else {
+                let retry = self
+                    .lost
+                    .as_ref()
+                    .map_or_else(channel::never, |lost| channel::at(lost.retry_at));
+
+                // woken by stream controls, e.g. to play
+                select! {
+                    recv(self.command_rx) -> cmd => match cmd {
+                        Ok(cmd) => Some(cmd),
+                        Err(_) => {
+                            tracing::error!("command channel closed");
+                            break;
+                        }
+                    },
+                    recv(self.output.wake()) -> _ => None,
+                    recv(retry) -> _ => None,
+                }
+            };
+
+            if let Some(cmd) = cmd {
                 match cmd {
                     Command::Load(file, res_tx) => {
-                        if let Err(_) = self.handle_load(file, res_tx) {
+                        if self.handle_load(file, res_tx).is_err() {
+                            tracing::error!("response channel closed");
+                        }
+                    }
+                    Command::Preload(file, res_tx) => {
+                        if self.handle_preload(file, res_tx).is_err() {
                             tracing::error!("response channel closed");
                         }
                     }
                     Command::Prepare(res_tx) => {
-                        if let Err(_) = self.handle_prepare(res_tx) {
+                        if self.handle_prepare(res_tx).is_err() {
+                            tracing::error!("response channel closed");
+                        }
+                    }
+                    Command::Flush => {
+                        self.output.flush();
+                        self.fade = None;
+                    }
+                    Command::SetCrossfade(crossfade) => {
+                        self.crossfade = crossfade;
+                    }
+                    Command::SetVolume(volume) => {
+                        self.output.set_volume(volume);
+                    }
+                    Command::SetTempo(tempo, res_tx) => {
+                        if self.handle_set_tempo(tempo, res_tx).is_err() {
+                            tracing::error!("response channel closed");
+                            break;
+                        }
+                    }
+                    Command::SetFilter(filter, res_tx) => {
+                        if self.handle_set_filter(filter, res_tx).is_err() {
+                            tracing::error!("response channel closed");
+                            break;
+                        }
+                    }
+                    Command::SetEqualizer(bands) => {
+                        self.equalizer = bands;
+                        for stream in self.stream.iter_mut().chain(self.next.iter_mut()) {
+                            stream.set_equalizer(self.equalizer.as_deref());
+                        }
+                    }
+                    Command::Stats(res_tx) => {
+                        if res_tx.send(self.output.stats()).is_err() {
                             tracing::error!("response channel closed");
                         }
                     }
+                    Command::SetDevice(name, res_tx) => {
+                        if self.handle_set_device(name, res_tx).is_err() {
+                            tracing::error!("response or event channel closed");
+                            break;
+                        }
+                    }
+                    Command::SetReplayGain(replay_gain) => {
+                        self.replay_gain = replay_gain;
+                        for stream in self.stream.iter_mut().chain(self.next.iter_mut()) {
+                            stream.set_replay_gain(&self.replay_gain);
+                        }
+                    }
                 }
-            } else {
-                tracing::error!("command channel closed");
+            }
+
+            if self.lost.is_some() {
+                if self.reconnect().is_err() {
+                    tracing::error!("event channel closed");
+                    break;
+                }
+                continue;
+            }
+
+            if self.process().is_err() {
+                tracing::error!("event channel closed");
                 break;
             }
+
+            // reported as a tracing event
+            let _ = self.output.check_underruns();
+            if self.output.is_device_lost() {
+                self.lose_device();
+            }
         }
     }
 }
 
 impl AudioPlayerActor {
+    fn is_playing(&self) -> bool {
+        self.stream
+            .as_ref()
+            .is_some_and(|stream| stream.state().is_playing())
+    }
+
     /// # Returns
     /// + `Err` if the response could not be handled.
     fn handle_load(
@@ -72,16 +310,19 @@ impl AudioPlayerActor {
         file: PathBuf,
         res_tx: channel::Sender<LoadResponse>,
     ) -> Result<(), error::Channel> {
-        let audio = match lib::AudioFile::from_path(file).map_err(error::Load::Audio) {
-            Ok(audio) => audio,
-            Err(err) => {
-                tracing::debug!(?err);
-                res_tx.send(Err(err))?;
-                return Ok(());
+        if let Some(current) = self.stream.as_mut() {
+            if let Some(track) = current.take_decoded() {
+                self.cache.insert(track);
             }
+        }
+
+        let opened = if self.builder.is_bit_perfect() {
+            self.open_bit_perfect(file)
+        } else {
+            self.open(file)
         };
 
-        let stream = match self.builder.load(audio).map_err(error::Load::Stream) {
+        let stream = match opened {
             Ok(stream) => stream,
             Err(err) => {
                 tracing::debug!(?err);
@@ -90,32 +331,602 @@ impl AudioPlayerActor {
             }
         };
 
+        let current_done = self
+            .stream
+            .as_ref()
+            .is_some_and(|stream| stream.state().is_done());
+        let fade = self
+            .crossfade
+            .filter(|crossfade| crossfade.on_skip && self.output.is_playing());
+
+        match (self.stream.take(), fade) {
+            (Some(current), Some(crossfade)) if !current_done => {
+                // keep playing through the fade
+                stream.set_state(lib::StreamState::Play);
+                let _ = self
+                    .fade
+                    .insert(lib::Crossfade::new(current, &crossfade, &self.output));
+            }
+            (Some(_), _) if !current_done => {
+                // cut off the current song
+                self.output.flush();
+                self.fade = None;
+            }
+            _ => {}
+        }
+
+        self.ending = None;
         let _ = self.stream.insert(stream);
         res_tx.send(Ok(()))?;
         Ok(())
     }
 
+    /// Opens the song to play after the current one, so it follows without a gap.
+    /// Like any other song, it is cached once it has played all the way through.
+    ///
+    /// # Returns
+    /// + `Err` if the response could not be handled.
+    fn handle_preload(
+        &mut self,
+        file: Option<PathBuf>,
+        res_tx: channel::Sender<LoadResponse>,
+    ) -> Result<(), error::Channel> {
+        let Some(file) = file else {
+            self.next = None;
+            res_tx.send(Ok(()))?;
+            return Ok(());
+        };
+
+        let opened = if self.builder.is_bit_perfect() {
+            match self.probe(&file) {
+                Ok((source, _)) if self.needs_reconfigure(&source) => {
+                    // loaded once the current song is done, into an output in its format
+                    tracing::debug!("{file:?} is played in another format, so not without a gap");
+                    self.next = None;
+                    res_tx.send(Ok(()))?;
+                    return Ok(());
+                }
+                Ok((_, audio)) => self.open_from(file, audio),
+                Err(err) => Err(err),
+            }
+        } else {
+            self.open(file)
+        };
+
+        match opened {
+            Ok(stream) => {
+                let _ = self.next.insert(stream);
+                res_tx.send(Ok(()))?;
+            }
+            Err(err) => {
+                tracing::debug!(?err);
+                self.next = None;
+                res_tx.send(Err(err))?;
+            }
+        }
+
+        Ok(())
+    }
+
     /// # Returns
     /// + `Err` if the response could not be handled.
     fn handle_prepare(
         &mut self,
         res_tx: channel::Sender<PrepareResponse>,
     ) -> Result<(), error::Channel> {
-        let Some(stream) = self.stream.as_mut() else {
+        let Some(stream) = self.stream.as_ref() else {
             res_tx.send(Err(error::Play::NoStream))?;
             return Ok(());
         };
 
-        res_tx.send(Ok(stream.state())).unwrap();
-        if let Err(err) = stream.load().map_err(Event::StreamErr) {
+        res_tx.send(Ok(StreamHandle::from(stream)))?;
+        Ok(())
+    }
+
+    /// Changes the tempo of the current and preloaded songs,
+    /// or keeps the old tempo of both if either can not be changed.
+    ///
+    /// # Returns
+    /// + `Err` if the response channel closed.
+    fn handle_set_tempo(
+        &mut self,
+        tempo: f32,
+        res_tx: channel::Sender<TempoResponse>,
+    ) -> Result<(), error::Channel> {
+        let mut res = Ok(());
+        for stream in self.stream.iter_mut().chain(self.next.iter_mut()) {
+            if let Err(err) = stream.set_tempo(tempo, &mut self.output) {
+                tracing::debug!(?err);
+                res = Err(err);
+                break;
+            }
+        }
+
+        if res.is_ok() {
+            self.tempo = tempo;
+        } else {
+            for stream in self.stream.iter_mut().chain(self.next.iter_mut()) {
+                if let Err(err) = stream.set_tempo(self.tempo, &mut self.output) {
+                    tracing::debug!(?err);
+                }
+            }
+        }
+
+        res_tx.send(res)?;
+        Ok(())
+    }
+
+    /// Runs the current and preloaded songs through a new filter,
+    /// or keeps the old filter of both if either can not use it.
+    ///
+    /// # Returns
+    /// + `Err` if the response channel closed.
+    fn handle_set_filter(
+        &mut self,
+        filter: Option<String>,
+        res_tx: channel::Sender<FilterResponse>,
+    ) -> Result<(), error::Channel> {
+        if let Some(spec) = filter.as_deref() {
+            if let Err(err) = self.output.check_filter(spec) {
+                tracing::debug!(?err);
+                res_tx.send(Err(err))?;
+                return Ok(());
+            }
+        }
+
+        let mut res = Ok(());
+        for stream in self.stream.iter_mut().chain(self.next.iter_mut()) {
+            if let Err(err) = stream.set_filter(filter.clone(), &mut self.output) {
+                tracing::debug!(?err);
+                res = Err(err);
+                break;
+            }
+        }
+
+        if res.is_ok() {
+            self.filter = filter;
+        } else {
+            for stream in self.stream.iter_mut().chain(self.next.iter_mut()) {
+                if let Err(err) = stream.set_filter(self.filter.clone(), &mut self.output) {
+                    tracing::debug!(?err);
+                }
+            }
+        }
+
+        res_tx.send(res)?;
+        Ok(())
+    }
+
+    fn open(&mut self, file: PathBuf) -> Result<lib::AudioStream, error::Load> {
+        self.open_from(file, None)
+    }
+
+    /// # Arguments
+    /// + `audio`: The song's file, if already opened. Used if the song is not cached.
+    fn open_from(
+        &mut self,
+        file: PathBuf,
+        audio: Option<lib::AudioFile>,
+    ) -> Result<lib::AudioStream, error::Load> {
+        let loudness = self.loudness.get(&file);
+        let cached = self
+            .cache
+            .get(&file, self.builder.decode_format())
+            .and_then(|track| self.builder.load_decoded(track, &self.output));
+
+        let mut stream = match cached {
+            Some(stream) => {
+                tracing::trace!("{file:?} loaded from cache");
+                stream
+            }
+            None => {
+                let audio = match audio {
+                    Some(audio) => audio,
+                    None => lib::AudioFile::from_path(file).map_err(error::Load::Audio)?,
+                };
+                let mut stream = self
+                    .builder
+                    .load(audio, &self.output)
+                    .map_err(error::Load::Stream)?;
+
+                let reservation = stream
+                    .decoded_size()
+                    .and_then(|size| self.cache.reserve(size));
+                if let Some(reservation) = reservation {
+                    stream.record_decoded(reservation);
+                }
+                stream
+            }
+        };
+
+        if let Some(loudness) = loudness.filter(|_| stream.replay_gain().is_empty()) {
+            stream.set_replay_gain_tags(lib::ReplayGainTags::from_loudness(&loudness));
+        }
+        stream.set_replay_gain(&self.replay_gain);
+        tracing::debug!(replay_gain = ?stream.replay_gain());
+        stream.set_equalizer(self.equalizer.as_deref());
+        stream
+            .set_tempo(self.tempo, &mut self.output)
+            .map_err(error::Load::Filter)?;
+        stream
+            .set_filter(self.filter.clone(), &mut self.output)
+            .map_err(error::Load::Filter)?;
+
+        Ok(stream)
+    }
+
+    /// Applies the current stream's state to the output,
+    /// and loads its next packet if it is playing.
+    ///
+    /// # Returns
+    /// + `Err` if the event channel closed.
+    fn process(&mut self) -> Result<(), error::Channel> {
+        if self.ending.is_some_and(|end| self.output.played() >= end) {
+            self.ending = None;
+            self.event_tx.send(Event::Done)?;
+        }
+
+        let Some(stream) = self.stream.as_mut() else {
+            return Ok(());
+        };
+
+        if let Err(err) = stream.apply_pending_seek(&mut self.output) {
+            tracing::debug!(?err);
+            self.event_tx.send(Event::Warning(err))?;
+        }
+
+        let state = stream.state();
+        let output_state = match state {
+            lib::StreamState::Play => self.output.play(),
+            lib::StreamState::Pause => self.output.pause(),
+            lib::StreamState::Stop | lib::StreamState::Done => return Ok(()),
+        };
+        if let Err(err) = output_state {
             tracing::debug!(?err);
-            self.event_tx.send(err)?;
+            if matches!(err, lib::error::AudioStream::DeviceNotAvailable) {
+                self.lose_device();
+                return Ok(());
+            }
+
+            stream.set_state(lib::StreamState::Stop);
+            self.event_tx.send(Event::StreamErr(err))?;
+            return Ok(());
+        }
+
+        if !state.is_playing() {
+            return Ok(());
+        }
+
+        if let Some(crossfade) = self.crossfade {
+            let fade_start = stream
+                .decode_remaining()
+                .is_some_and(|remaining| remaining <= crossfade.duration);
+
+            if fade_start && self.fade.is_none() && self.next.is_some() {
+                return self.advance();
+            }
+        }
+
+        match stream.load_packet(&mut self.output, self.fade.as_mut()) {
+            Ok(true) => {}
+            Ok(false) => return self.advance(),
+            Err(err) => {
+                tracing::debug!(?err);
+                stream.set_state(lib::StreamState::Stop);
+                self.event_tx.send(Event::Abandoned(err))?;
+                return Ok(());
+            }
+        }
+
+        if self.fade.as_ref().is_some_and(|fade| fade.is_done()) {
+            if let Some(fade) = self.fade.take() {
+                let mut outgoing = fade.into_outgoing();
+                outgoing.set_state(lib::StreamState::Done);
+                if let Some(track) = outgoing.take_decoded() {
+                    self.cache.insert(track);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stops playing until an output device is found, see [`Self::reconnect`].
+    fn lose_device(&mut self) {
+        if self.lost.is_some() {
+            return;
+        }
+
+        let name = self.builder.device().name().ok();
+        tracing::warn!(device = ?name, "output device lost, playback paused");
+        let _ = self.lost.insert(LostDevice {
+            name,
+            retry_at: Instant::now(),
+        });
+    }
+
+    /// Looks for the lost device, falling back to the default device,
+    /// and moves playback to a new output on it.
+    /// Tries again later if neither can be played through.
+    ///
+    /// # Returns
+    /// + `Err` if the event channel closed.
+    fn reconnect(&mut self) -> Result<(), error::Channel> {
+        let Some(lost) = self.lost.as_mut() else {
             return Ok(());
         };
+        if Instant::now() < lost.retry_at {
+            return Ok(());
+        }
+        lost.retry_at = Instant::now() + RECONNECT_INTERVAL;
+
+        let same_device = lost
+            .name
+            .as_deref()
+            .and_then(|name| device::find_output(&self.host, name))
+            .map(|device| (device, self.builder.stream_config().clone()));
+
+        let mut output = None;
+        for (device, stream_config) in same_device.into_iter().chain(device::default_output(
+            &self.host,
+            &self.config_preference(),
+        )) {
+            let name = device.name().ok();
+            self.builder.set_device(device, stream_config);
+            match self.builder.build_output() {
+                Ok(built) => {
+                    tracing::info!(device = ?name, "playing through output device");
+                    output = Some(built);
+                    break;
+                }
+                Err(err) => tracing::debug!(?err, device = ?name),
+            }
+        }
+
+        let Some(output) = output else {
+            return Ok(());
+        };
+        self.lost = None;
+        self.replace_output(output)
+    }
+
+    /// # Returns
+    /// + `Err` if the response or event channel closed.
+    fn handle_set_device(
+        &mut self,
+        name: String,
+        res_tx: channel::Sender<DeviceResponse>,
+    ) -> Result<(), error::Channel> {
+        let output = match self.build_output_on(&name) {
+            Ok(output) => output,
+            Err(err) => {
+                tracing::debug!(?err);
+                res_tx.send(Err(err))?;
+                return Ok(());
+            }
+        };
+
+        if self.lost.take().is_none() {
+            // fade out what is playing on the old device
+            if let Err(err) = self.output.pause() {
+                tracing::debug!(?err);
+            }
+        }
+
+        res_tx.send(Ok(()))?;
+        self.replace_output(output)
+    }
+
+    /// Builds an output on the device with the given name.
+    /// The builder is left on its current device if the output can not be built.
+    fn build_output_on(&mut self, name: &str) -> Result<lib::AudioOutput, error::Device> {
+        let device = device::find_output(&self.host, name).ok_or(error::Device::NotFound)?;
+        let stream_config = device::stream_config(&device, &self.config_preference())
+            .ok_or(error::Device::NoConfig)?;
+
+        let previous = (
+            self.builder.device().clone(),
+            self.builder.stream_config().clone(),
+        );
+        self.builder.set_device(device, stream_config);
+        match self.builder.build_output() {
+            Ok(output) => Ok(output),
+            Err(err) => {
+                let (device, stream_config) = previous;
+                self.builder.set_device(device, stream_config);
+                Err(error::Device::Output(err))
+            }
+        }
+    }
+
+    /// Opens a song, first opening the device in its format, see [`Self::configure_for`].
+    fn open_bit_perfect(&mut self, file: PathBuf) -> Result<lib::AudioStream, error::Load> {
+        let (source, audio) = self.probe(&file)?;
+        self.configure_for(&file, &source);
+        self.open_from(file, audio)
+    }
+
+    /// Reads the format of a song's audio,
+    /// from the cache if it holds the song, otherwise by opening its file.
+    ///
+    /// # Returns
+    /// The song's file, if it was opened, to load the song from.
+    fn probe(
+        &self,
+        file: &PathBuf,
+    ) -> Result<(lib::SourceFormat, Option<lib::AudioFile>), error::Load> {
+        if let Some(source) = self.cache.source_format(file) {
+            return Ok((source, None));
+        }
+
+        let audio = lib::AudioFile::from_path(file.clone()).map_err(error::Load::Audio)?;
+        let source = audio.source_format().map_err(error::Load::Stream)?;
+        Ok((source, Some(audio)))
+    }
+
+    /// Opens the device in the format of a song about to be loaded, for bit-perfect playback.
+    /// If a new output is built, the current and preloaded songs are dropped,
+    /// as they were decoded for the old one.
+    /// Reports when the song can not be played bit-perfect.
+    fn configure_for(&mut self, file: &PathBuf, source: &lib::SourceFormat) {
+        let previous = self.builder.stream_config().clone();
+        match self.builder.configure_for(source) {
+            Ok(false) => {}
+            Ok(true) => match self.builder.build_output() {
+                Ok(mut output) => {
+                    let config = self.builder.stream_config();
+                    tracing::info!(
+                        "playing bit-perfect at {} Hz, {} channels, {}",
+                        config.sample_rate().0,
+                        config.channels(),
+                        config.sample_format()
+                    );
+
+                    // fade out what is playing in the old format
+                    if let Err(err) = self.output.pause() {
+                        tracing::debug!(?err);
+                    }
+
+                    output.set_volume(self.output.volume());
+                    output.set_fade(self.output.fade());
+                    self.output = output;
+                    self.stream = None;
+                    self.next = None;
+                    self.fade = None;
+                    self.ending = None;
+                }
+                Err(err) => {
+                    tracing::warn!(?err, "{file:?} can not be played bit-perfect");
+                    let device = self.builder.device().clone();
+                    self.builder.set_device(device, previous);
+                }
+            },
+            Err(err) => tracing::warn!(?err, "{file:?} can not be played bit-perfect"),
+        }
+    }
+
+    /// Whether the device has to be opened in another format to play a song bit-perfect.
+    fn needs_reconfigure(&self, source: &lib::SourceFormat) -> bool {
+        self.builder
+            .bit_perfect_config(source)
+            .is_ok_and(|config| config != *self.builder.stream_config())
+    }
 
-        if stream.state().lock().unwrap().is_done() {
+    /// Config to open a device with, preferring the sample rate of the song playing.
+    fn config_preference(&self) -> device::ConfigPreference {
+        let native_rate = self
+            .stream
+            .as_ref()
+            .map(|stream| stream.source_format().sample_rate());
+
+        device::ConfigPreference {
+            native_rate,
+            ..self.preference
+        }
+    }
+
+    /// Moves playback to a new output, keeping the volume and fade of the current one.
+    /// Streams are loaded into the new output,
+    /// with the current song resuming where it left off.
+    ///
+    /// # Returns
+    /// + `Err` if the event channel closed.
+    fn replace_output(&mut self, mut output: lib::AudioOutput) -> Result<(), error::Channel> {
+        output.set_volume(self.output.volume());
+        output.set_fade(self.output.fade());
+        self.output = output;
+
+        // songs are decoded to the format of the output they were loaded into
+        self.fade = None;
+        if let Some(next) = self.next.take() {
+            match self.open(next.path().clone()) {
+                Ok(stream) => {
+                    let _ = self.next.insert(stream);
+                }
+                Err(err) => tracing::debug!(?err),
+            }
+        }
+
+        let Some(current) = self.stream.take() else {
+            return Ok(());
+        };
+        if self.ending.take().is_some() {
+            // the rest of the song was queued to the old output
+            let _ = self.stream.insert(current);
             self.event_tx.send(Event::Done)?;
+            return Ok(());
         }
+
+        let state = current.state();
+        let position = current.position();
+        let mut stream = match self.open(current.path().clone()) {
+            Ok(stream) => stream,
+            Err(err) => {
+                tracing::debug!(?err);
+                current.set_state(lib::StreamState::Stop);
+                let _ = self.stream.insert(current);
+                let err = match err {
+                    error::Load::Audio(err) | error::Load::Stream(err) => {
+                        lib::error::AudioStream::Open(err)
+                    }
+                    error::Load::Filter(err) => err,
+                };
+                self.event_tx.send(Event::Abandoned(err))?;
+                return Ok(());
+            }
+        };
+
+        if !position.is_zero() {
+            if let Err(err) = stream.seek(lib::Seek::To(position), &mut self.output) {
+                tracing::debug!(?err);
+                self.event_tx.send(Event::StreamErr(err))?;
+            }
+        }
+        stream.set_state(state);
+
+        let handle = StreamHandle::from(&stream);
+        let _ = self.stream.insert(stream);
+        self.event_tx.send(Event::Reconnected(handle))?;
+        Ok(())
+    }
+
+    /// Moves on from the current stream to the preloaded one, if any.
+    /// The preloaded stream feeds the same output, so plays without a gap,
+    /// or fades in over the current stream if crossfading.
+    ///
+    /// # Returns
+    /// + `Err` if the event channel closed.
+    fn advance(&mut self) -> Result<(), error::Channel> {
+        let Some(current) = self.stream.as_mut() else {
+            return Ok(());
+        };
+
+        if let Some(track) = current.take_decoded() {
+            self.cache.insert(track);
+        }
+
+        let state = current.state();
+        current.set_state(lib::StreamState::Done);
+        match self.next.take() {
+            Some(next) => {
+                next.set_state(state);
+
+                let handle = StreamHandle::from(&next);
+                let outgoing = self.stream.replace(next);
+                self.fade = match (outgoing, self.crossfade) {
+                    (Some(outgoing), Some(crossfade)) => {
+                        Some(lib::Crossfade::new(outgoing, &crossfade, &self.output))
+                    }
+                    _ => None,
+                };
+                self.event_tx.send(Event::Advanced(handle))?;
+            }
+            None => {
+                // report the end once the last of the song has been played
+                let _ = self.ending.insert(self.output.written());
+            }
+        }
+
         Ok(())
     }
 }
@@ -123,6 +934,7 @@ impl AudioPlayerActor {
 pub mod error {
     use crossbeam::channel;
     use ffmpeg_next as ffm;
+    use sensit_audio_cli as lib;
 
     #[derive(Debug)]
     pub enum Load {
@@ -132,6 +944,9 @@ pub mod error {
         /// Could not create [`AudioStream`](lib::AudioStream)
         /// from the [`AudioFile`](lib::AudioFile) .
         Stream(ffm::Error),
+
+        /// Could not apply the set tempo or filter to the stream.
+        Filter(lib::error::AudioStream),
     }
 
     #[derive(Debug)]
@@ -140,6 +955,18 @@ pub mod error {
         NoStream,
     }
 
+    #[derive(Debug)]
+    pub enum Device {
+        /// No output device has the name.
+        NotFound,
+
+        /// The device supports no config audio can be played with.
+        NoConfig,
+
+        /// Could not open the device's output stream.
+        Output(lib::error::AudioOutput),
+    }
+
     /// A channel was closed.
     pub struct Channel;
     impl<T> From<channel::SendError<T>> for Channel {
@@ -0,0 +1,315 @@
+//! Memory reused between tracks, so skipping through a playlist does not churn allocations.
+use ffmpeg_next as ffm;
+use ringbuf::traits::*;
+use std::sync::{Arc, Mutex};
+
+/// Most buffers of each kind held for reuse.
+const MAX_POOLED: usize = 8;
+
+/// Sample buffers with more capacity than this are freed rather than pooled,
+/// so a one off large buffer is not held for the whole session.
+const MAX_POOLED_SAMPLES: usize = 1 << 20;
+
+/// Buffers and frames returned by finished streams and outputs,
+/// to be handed out to the next ones.
+/// Clones share the same pool.
+#[derive(Clone, Debug, Default)]
+pub struct BufferPool {
+    pools: Arc<Mutex<Pools>>,
+}
+
+#[derive(derive_more::Debug, Default)]
+struct Pools {
+    samples: Vec<Vec<f32>>,
+
+    /// Frames resampled audio is written to.
+    #[debug(skip)]
+    frames: Vec<ffm::frame::Audio>,
+
+    /// Ring buffers of outputs.
+    /// Only reused once the output's stream has dropped its half.
+    #[debug(skip)]
+    ring_buffers: Vec<Arc<ringbuf::HeapRb<f32>>>,
+    stats: PoolStats,
+}
+
+/// Allocation counts of a [`BufferPool`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PoolStats {
+    /// Scratch buffers of streams.
+    pub sample_buffers: Allocations,
+
+    /// Frames audio is resampled into.
+    /// Reused each time a frame is resampled into the storage of an earlier one.
+    pub frames: Allocations,
+
+    /// Ring buffers of outputs.
+    pub ring_buffers: Allocations,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Allocations {
+    /// Number of times memory was allocated.
+    pub allocated: u64,
+
+    /// Number of times memory was reused instead.
+    pub reused: u64,
+
+    /// Number of buffers held by the pool.
+    pub pooled: usize,
+}
+
+impl BufferPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let pools = self.pools.lock().unwrap();
+        let mut stats = pools.stats;
+        stats.sample_buffers.pooled = pools.samples.len();
+        stats.frames.pooled = pools.frames.len();
+        stats.ring_buffers.pooled = pools.ring_buffers.len();
+        stats
+    }
+
+    /// Frees the buffers held by the pool.
+    pub fn clear(&self) {
+        let mut pools = self.pools.lock().unwrap();
+        pools.samples.clear();
+        pools.frames.clear();
+        pools.ring_buffers.clear();
+    }
+
+    /// Takes an empty sample buffer.
+    pub(crate) fn take_samples(&self) -> Vec<f32> {
+        let mut pools = self.pools.lock().unwrap();
+        match pools.samples.pop() {
+            Some(samples) => {
+                pools.stats.sample_buffers.reused += 1;
+                samples
+            }
+            None => {
+                pools.stats.sample_buffers.allocated += 1;
+                Vec::new()
+            }
+        }
+    }
+
+    /// Returns a sample buffer for reuse.
+    pub(crate) fn put_samples(&self, mut samples: Vec<f32>) {
+        if samples.capacity() == 0 || samples.capacity() > MAX_POOLED_SAMPLES {
+            return;
+        }
+
+        let mut pools = self.pools.lock().unwrap();
+        if pools.samples.len() < MAX_POOLED {
+            samples.clear();
+            pools.samples.push(samples);
+        }
+    }
+
+    /// Takes a frame to resample audio into, see [`Self::fit_frame`].
+    pub(crate) fn take_frame(&self) -> ffm::frame::Audio {
+        self.pools
+            .lock()
+            .unwrap()
+            .frames
+            .pop()
+            .unwrap_or_else(ffm::frame::Audio::empty)
+    }
+
+    /// Returns a frame for reuse.
+    pub(crate) fn put_frame(&self, frame: ffm::frame::Audio) {
+        if frame.planes() == 0 {
+            // no storage to reuse
+            return;
+        }
+
+        let mut pools = self.pools.lock().unwrap();
+        if pools.frames.len() < MAX_POOLED {
+            pools.frames.push(frame);
+        }
+    }
+
+    /// Readies a frame to take the output of a resampler,
+    /// keeping its storage if it fits, otherwise allocating new storage.
+    ///
+    /// # Arguments
+    /// + `input_samples`: Number of samples per channel about to be resampled.
+    pub(crate) fn fit_frame(
+        &self,
+        frame: &mut ffm::frame::Audio,
+        resampler: &mut ffm::software::resampling::context::Context,
+        input_samples: usize,
+    ) {
+        let output = *resampler.output();
+
+        // upper bound on the samples the resampler outputs, including those it holds back
+        let input_samples = i32::try_from(input_samples).unwrap_or(i32::MAX);
+        // SAFETY: the pointer is to the resampler's context, which it keeps initialized
+        // for as long as it lives, and is only read for the length of the call.
+        let samples =
+            unsafe { ffm::ffi::swr_get_out_samples(resampler.as_mut_ptr(), input_samples) };
+
+        // negative on error, e.g. for more samples than fit in an `i32`
+        let samples = usize::try_from(samples).unwrap_or(0).max(1);
+
+        let fits = frame.format() == output.format
+            && frame.channel_layout() == output.channel_layout
+            && frame_capacity(frame) >= samples;
+
+        let mut pools = self.pools.lock().unwrap();
+        if fits {
+            // the resampler writes up to the frame's sample count
+            frame.set_samples(frame_capacity(frame));
+            pools.stats.frames.reused += 1;
+        } else {
+            *frame = ffm::frame::Audio::new(output.format, samples, output.channel_layout);
+            pools.stats.frames.allocated += 1;
+        }
+    }
+
+    /// Takes an empty ring buffer of the given capacity.
+    pub(crate) fn take_ring_buffer(&self, capacity: usize) -> Arc<ringbuf::HeapRb<f32>> {
+        let mut pools = self.pools.lock().unwrap();
+        let idx = pools.ring_buffers.iter_mut().position(|rb| {
+            // the output's stream may still hold the other half
+            Arc::get_mut(rb).is_some_and(|rb| rb.capacity().get() == capacity)
+        });
+
+        match idx {
+            Some(idx) => {
+                let mut rb = pools.ring_buffers.swap_remove(idx);
+                Arc::get_mut(&mut rb).unwrap().clear();
+                pools.stats.ring_buffers.reused += 1;
+                rb
+            }
+            None => {
+                pools.stats.ring_buffers.allocated += 1;
+                Arc::new(ringbuf::HeapRb::new(capacity))
+            }
+        }
+    }
+
+    /// Returns the ring buffer of an output for reuse,
+    /// once the output's stream has dropped its half.
+    pub(crate) fn put_ring_buffer(&self, rb: Arc<ringbuf::HeapRb<f32>>) {
+        let mut pools = self.pools.lock().unwrap();
+        if pools.ring_buffers.len() == MAX_POOLED {
+            pools.ring_buffers.remove(0);
+        }
+        pools.ring_buffers.push(rb);
+    }
+}
+
+/// Number of samples per channel a packed frame has storage for.
+fn frame_capacity(frame: &ffm::frame::Audio) -> usize {
+    let frame_bytes = frame.format().bytes() * frame.channels() as usize;
+    if frame.planes() == 0 || frame_bytes == 0 {
+        return 0;
+    }
+
+    frame.data(0).len() / frame_bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_are_reused() {
+        let pool = BufferPool::new();
+        let mut samples = pool.take_samples();
+        samples.extend_from_slice(&[0.5; 64]);
+        let capacity = samples.capacity();
+        pool.put_samples(samples);
+        assert_eq!(pool.stats().sample_buffers.pooled, 1);
+
+        let samples = pool.take_samples();
+        assert!(samples.is_empty());
+        assert_eq!(samples.capacity(), capacity);
+
+        let stats = pool.stats().sample_buffers;
+        assert_eq!(stats.allocated, 1);
+        assert_eq!(stats.reused, 1);
+        assert_eq!(stats.pooled, 0);
+    }
+
+    #[test]
+    fn samples_pool_is_bounded() {
+        let pool = BufferPool::new();
+        for _ in 0..MAX_POOLED + 1 {
+            pool.put_samples(Vec::with_capacity(16));
+        }
+        assert_eq!(pool.stats().sample_buffers.pooled, MAX_POOLED);
+
+        // nothing to reuse, or too large to hold on to
+        let pool = BufferPool::new();
+        pool.put_samples(Vec::new());
+        pool.put_samples(Vec::with_capacity(MAX_POOLED_SAMPLES + 1));
+        assert_eq!(pool.stats().sample_buffers.pooled, 0);
+    }
+
+    #[test]
+    fn ring_buffer_of_same_capacity_is_reused() {
+        let pool = BufferPool::new();
+        let mut rb = pool.take_ring_buffer(64);
+        Arc::get_mut(&mut rb).unwrap().push_slice(&[1.0; 16]);
+        pool.put_ring_buffer(rb);
+
+        let other = pool.take_ring_buffer(128);
+        assert_eq!(other.capacity().get(), 128);
+
+        let rb = pool.take_ring_buffer(64);
+        assert!(rb.is_empty());
+
+        let stats = pool.stats().ring_buffers;
+        assert_eq!(stats.allocated, 2);
+        assert_eq!(stats.reused, 1);
+        assert_eq!(stats.pooled, 0);
+    }
+
+    #[test]
+    fn shared_ring_buffer_is_not_reused() {
+        let pool = BufferPool::new();
+        let rb = pool.take_ring_buffer(64);
+        let stream_half = rb.clone();
+        pool.put_ring_buffer(rb);
+
+        let _ = pool.take_ring_buffer(64);
+        assert_eq!(pool.stats().ring_buffers.allocated, 2);
+
+        // the stream let go of its half
+        drop(stream_half);
+        let _ = pool.take_ring_buffer(64);
+        assert_eq!(pool.stats().ring_buffers.reused, 1);
+    }
+
+    #[test]
+    fn oldest_ring_buffer_is_evicted() {
+        let pool = BufferPool::new();
+        for capacity in 1..=MAX_POOLED + 1 {
+            pool.put_ring_buffer(Arc::new(ringbuf::HeapRb::new(capacity)));
+        }
+        assert_eq!(pool.stats().ring_buffers.pooled, MAX_POOLED);
+
+        let _ = pool.take_ring_buffer(2);
+        assert_eq!(pool.stats().ring_buffers.reused, 1);
+
+        let _ = pool.take_ring_buffer(1);
+        assert_eq!(pool.stats().ring_buffers.allocated, 1);
+    }
+
+    #[test]
+    fn clear_frees_pooled_buffers() {
+        let pool = BufferPool::new();
+        pool.put_samples(Vec::with_capacity(16));
+        pool.put_ring_buffer(Arc::new(ringbuf::HeapRb::new(16)));
+        pool.clear();
+
+        let stats = pool.stats();
+        assert_eq!(stats.sample_buffers.pooled, 0);
+        assert_eq!(stats.ring_buffers.pooled, 0);
+    }
+}
@@ -0,0 +1,224 @@
+//! Loudness normalization from ReplayGain tags.
+use crate::{Loudness, REPLAY_GAIN_REFERENCE};
+use ffmpeg_next as ffm;
+
+const TAG_TRACK_GAIN: &str = "REPLAYGAIN_TRACK_GAIN";
+const TAG_TRACK_PEAK: &str = "REPLAYGAIN_TRACK_PEAK";
+const TAG_ALBUM_GAIN: &str = "REPLAYGAIN_ALBUM_GAIN";
+const TAG_ALBUM_PEAK: &str = "REPLAYGAIN_ALBUM_PEAK";
+const TAG_R128_TRACK_GAIN: &str = "R128_TRACK_GAIN";
+const TAG_R128_ALBUM_GAIN: &str = "R128_ALBUM_GAIN";
+
+/// R128 gains are relative to -23 LUFS, ReplayGain to -18 LUFS.
+const R128_TO_REPLAY_GAIN_DB: f32 = 5.0;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReplayGainMode {
+    /// Play tracks as they are.
+    #[default]
+    Off,
+
+    /// Normalize each track on its own.
+    Track,
+
+    /// Normalize tracks by their album,
+    /// keeping the differences in loudness between tracks of the same album.
+    /// Falls back to track gain if a track has no album gain.
+    Album,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ReplayGainConfig {
+    pub mode: ReplayGainMode,
+
+    /// Gain added to tracks' gain, in dB.
+    pub preamp: f32,
+
+    /// Loudness to normalize tracks to, in LUFS.
+    /// ReplayGain values are shifted from their reference loudness to this.
+    pub target_loudness: f32,
+
+    /// Lower the gain of tracks if it would push their peak past full scale.
+    pub prevent_clipping: bool,
+}
+
+impl Default for ReplayGainConfig {
+    fn default() -> Self {
+        Self {
+            mode: ReplayGainMode::default(),
+            preamp: 0.0,
+            target_loudness: REPLAY_GAIN_REFERENCE as f32,
+            prevent_clipping: true,
+        }
+    }
+}
+
+/// ReplayGain values of a track.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReplayGainTags {
+    /// Track gain, in dB.
+    pub track_gain: Option<f32>,
+
+    /// Track peak, as a linear amplitude.
+    pub track_peak: Option<f32>,
+
+    /// Album gain, in dB.
+    pub album_gain: Option<f32>,
+
+    /// Album peak, as a linear amplitude.
+    pub album_peak: Option<f32>,
+}
+
+impl ReplayGainTags {
+    /// Reads the tags from metadata.
+    /// Earlier metadata takes precedence.
+    ///
+    /// # Notes
+    /// + R128 gains (e.g. of Opus files) are converted to ReplayGain's reference loudness.
+    pub(crate) fn from_metadata(metadata: &[ffm::DictionaryRef]) -> Self {
+        let find = |key: &str, parse: fn(&str) -> Option<f32>| {
+            metadata
+                .iter()
+                .find_map(|metadata| metadata.get(key).and_then(parse))
+        };
+
+        Self {
+            track_gain: find(TAG_TRACK_GAIN, parse_gain)
+                .or_else(|| find(TAG_R128_TRACK_GAIN, parse_r128)),
+            track_peak: find(TAG_TRACK_PEAK, parse_peak),
+            album_gain: find(TAG_ALBUM_GAIN, parse_gain)
+                .or_else(|| find(TAG_R128_ALBUM_GAIN, parse_r128)),
+            album_peak: find(TAG_ALBUM_PEAK, parse_peak),
+        }
+    }
+
+    /// Track values from an analysis of the track,
+    /// for tracks without tags.
+    pub fn from_loudness(loudness: &Loudness) -> Self {
+        let track_gain = loudness
+            .integrated
+            .is_finite()
+            .then(|| (REPLAY_GAIN_REFERENCE - loudness.integrated) as f32);
+
+        Self {
+            track_gain,
+            track_peak: Some(10_f64.powf(loudness.true_peak / 20.0) as f32),
+            album_gain: None,
+            album_peak: None,
+        }
+    }
+
+    /// The track has no gain values.
+    pub fn is_empty(&self) -> bool {
+        self.track_gain.is_none() && self.album_gain.is_none()
+    }
+
+    /// Linear gain to play the track at.
+    /// Tracks without tags are played as they are.
+    pub fn gain(&self, config: &ReplayGainConfig) -> f32 {
+        let (gain, peak) = match config.mode {
+            ReplayGainMode::Off => return 1.0,
+            ReplayGainMode::Track => (self.track_gain, self.track_peak),
+            ReplayGainMode::Album => match self.album_gain {
+                Some(gain) => (Some(gain), self.album_peak),
+                None => (self.track_gain, self.track_peak),
+            },
+        };
+
+        let Some(gain) = gain else {
+            return 1.0;
+        };
+
+        let shift = config.target_loudness - REPLAY_GAIN_REFERENCE as f32;
+        let gain = 10_f32.powf((gain + config.preamp + shift) / 20.0);
+        match peak {
+            Some(peak) if config.prevent_clipping && peak > 0.0 => gain.min(1.0 / peak),
+            _ => gain,
+        }
+    }
+}
+
+/// Parses a gain of the form `-6.50 dB`.
+fn parse_gain(value: &str) -> Option<f32> {
+    let value = value.trim();
+    let value = value
+        .strip_suffix("dB")
+        .or(value.strip_suffix("db"))
+        .unwrap_or(value);
+
+    value.trim().parse().ok()
+}
+
+/// Parses a linear peak amplitude.
+fn parse_peak(value: &str) -> Option<f32> {
+    value.trim().parse().ok()
+}
+
+/// Parses an R128 gain, a Q7.8 fixed point number of dB.
+fn parse_r128(value: &str) -> Option<f32> {
+    let gain = value.trim().parse::<i16>().ok()?;
+    Some(gain as f32 / 256.0 + R128_TO_REPLAY_GAIN_DB)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(value: f32, expected: f32) {
+        assert!((value - expected).abs() < 1e-4, "{value} != {expected}");
+    }
+
+    #[test]
+    fn gain_parses_with_or_without_unit() {
+        assert_eq!(parse_gain("-6.50 dB"), Some(-6.5));
+        assert_eq!(parse_gain("+3.20 dB"), Some(3.2));
+        assert_eq!(parse_gain(" 1.5db "), Some(1.5));
+        assert_eq!(parse_gain("-2"), Some(-2.0));
+        assert_eq!(parse_gain("loud dB"), None);
+        assert_eq!(parse_gain(""), None);
+    }
+
+    #[test]
+    fn r128_gain_shifts_to_replay_gain_reference() {
+        // Q7.8: 256 is 1 dB
+        assert_near(parse_r128("0").unwrap(), 5.0);
+        assert_near(parse_r128("256").unwrap(), 6.0);
+        assert_near(parse_r128("-2560").unwrap(), -5.0);
+        assert_near(parse_r128(" -128 ").unwrap(), 4.5);
+        assert_eq!(parse_r128("1.5"), None);
+        assert_eq!(parse_r128("40000"), None);
+    }
+
+    #[test]
+    fn gain_is_limited_by_peak() {
+        let tags = ReplayGainTags {
+            track_gain: Some(6.0),
+            track_peak: Some(0.8),
+            ..Default::default()
+        };
+        let mut config = ReplayGainConfig {
+            mode: ReplayGainMode::Track,
+            ..Default::default()
+        };
+
+        assert_near(tags.gain(&config), 1.0 / 0.8);
+
+        config.prevent_clipping = false;
+        assert_near(tags.gain(&config), 10_f32.powf(6.0 / 20.0));
+    }
+
+    #[test]
+    fn gain_below_peak_limit_is_kept() {
+        let tags = ReplayGainTags {
+            track_gain: Some(-6.0),
+            track_peak: Some(0.8),
+            ..Default::default()
+        };
+        let config = ReplayGainConfig {
+            mode: ReplayGainMode::Track,
+            ..Default::default()
+        };
+
+        assert_near(tags.gain(&config), 10_f32.powf(-6.0 / 20.0));
+    }
+}
@@ -0,0 +1,296 @@
+//! Playback health of an output.
+use crate::PoolStats;
+use std::{
+    collections::VecDeque,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Number of underruns kept in [`PlaybackStats::recent_underruns`].
+const RECENT_UNDERRUNS: usize = 16;
+
+/// Length of played audio the recent decode speed is measured over.
+const DECODE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Health of an [`AudioOutput`](crate::AudioOutput)'s playback.
+#[derive(Clone, Debug)]
+pub struct PlaybackStats {
+    /// Time since the output was built.
+    pub uptime: Duration,
+
+    /// Number of times the device played silence because the buffer ran dry.
+    pub underruns: u64,
+
+    /// Silence played in place of missing audio.
+    pub underrun_silence: Duration,
+
+    /// Last underruns reported, oldest first.
+    pub recent_underruns: Vec<Underrun>,
+
+    /// Audio queued to the output, but not yet played.
+    pub buffered: Duration,
+
+    /// Audio the output's buffer holds when full.
+    pub buffer_capacity: Duration,
+
+    /// Length of the audio decoded per second spent decoding, since the output was built.
+    /// e.g. `50` decodes a minute of audio in just over a second.
+    /// Below `1` the decoder can not keep up with playback.
+    pub decode_speed: Option<f64>,
+
+    /// Decode speed over the last few seconds of audio.
+    pub recent_decode_speed: Option<f64>,
+
+    /// Memory allocated and reused by the output and the streams feeding it.
+    pub allocations: PoolStats,
+}
+
+impl PlaybackStats {
+    /// Fraction of the buffer filled, from `0` (empty) to `1` (full).
+    pub fn buffer_fill(&self) -> f32 {
+        if self.buffer_capacity.is_zero() {
+            return 0.0;
+        }
+
+        (self.buffered.as_secs_f32() / self.buffer_capacity.as_secs_f32()).min(1.0)
+    }
+}
+
+/// Buffer underruns, as seen by the thread feeding the output.
+/// Underruns in callbacks close together are reported together.
+#[derive(Clone, Copy, Debug)]
+pub struct Underrun {
+    /// Time since the output was built of the last of the underruns.
+    pub time: Duration,
+
+    /// Number of underruns.
+    pub count: u64,
+
+    /// Silence played in place of missing audio.
+    pub silence: Duration,
+}
+
+/// Underrun counts shared between an [`AudioOutput`](crate::AudioOutput)
+/// and its stream's callback.
+#[derive(Debug)]
+pub(crate) struct UnderrunCounter {
+    /// When the output was built.
+    started: Instant,
+
+    /// Whether the output should have audio to play:
+    /// audio was queued since the output was last flushed,
+    /// and the stream feeding it has not ended.
+    /// Running dry otherwise is not an underrun.
+    expecting: AtomicBool,
+    underruns: AtomicU64,
+
+    /// Frames of silence played because of underruns.
+    frames: AtomicU64,
+
+    /// Time of the last underrun, in microseconds since the output was built.
+    last: AtomicU64,
+}
+
+impl UnderrunCounter {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            expecting: AtomicBool::new(false),
+            underruns: AtomicU64::new(0),
+            frames: AtomicU64::new(0),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn is_expecting(&self) -> bool {
+        self.expecting.load(Ordering::Relaxed)
+    }
+
+    pub fn set_expecting(&self, expecting: bool) {
+        self.expecting.store(expecting, Ordering::Relaxed);
+    }
+
+    /// Counts an underrun in the output callback.
+    ///
+    /// # Arguments
+    /// + `frames`: Frames of silence played in place of missing audio.
+    pub fn record(&self, frames: u64) {
+        let time = u64::try_from(self.started.elapsed().as_micros()).unwrap_or(u64::MAX);
+        self.last.store(time, Ordering::Relaxed);
+        self.frames.fetch_add(frames, Ordering::Relaxed);
+        self.underruns.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn underruns(&self) -> u64 {
+        self.underruns.load(Ordering::Acquire)
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames.load(Ordering::Relaxed)
+    }
+
+    /// Time since the output was built of the last underrun.
+    pub fn last(&self) -> Duration {
+        Duration::from_micros(self.last.load(Ordering::Relaxed))
+    }
+}
+
+/// Underruns reported so far.
+#[derive(Debug, Default)]
+pub(crate) struct UnderrunLog {
+    reported: u64,
+    reported_frames: u64,
+    recent: VecDeque<Underrun>,
+}
+
+impl UnderrunLog {
+    /// Takes the underruns counted since the last report.
+    ///
+    /// # Returns
+    /// `None` if there were none.
+    pub fn report(&mut self, counter: &UnderrunCounter, sample_rate: u32) -> Option<Underrun> {
+        let underruns = counter.underruns();
+        if underruns == self.reported {
+            return None;
+        }
+
+        let frames = counter.frames();
+        let underrun = Underrun {
+            time: counter.last(),
+            count: underruns - self.reported,
+            silence: frames_to_duration(frames - self.reported_frames, sample_rate),
+        };
+
+        self.reported = underruns;
+        self.reported_frames = frames;
+        if self.recent.len() == RECENT_UNDERRUNS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(underrun);
+        Some(underrun)
+    }
+
+    pub fn recent(&self) -> Vec<Underrun> {
+        self.recent.iter().copied().collect()
+    }
+}
+
+/// Measures how fast audio is decoded.
+#[derive(Debug, Default)]
+pub(crate) struct DecodeMeter {
+    total: DecodeSpan,
+
+    /// Audio decoded since the last window filled.
+    current: DecodeSpan,
+
+    /// Last window of [`DECODE_WINDOW`] of audio.
+    previous: Option<DecodeSpan>,
+}
+
+impl DecodeMeter {
+    /// # Arguments
+    /// + `decoded`: Length of the audio decoded.
+    /// + `busy`: Time spent decoding it.
+    pub fn record(&mut self, decoded: Duration, busy: Duration) {
+        self.total.add(decoded, busy);
+        self.current.add(decoded, busy);
+        if self.current.decoded >= DECODE_WINDOW {
+            let _ = self.previous.insert(std::mem::take(&mut self.current));
+        }
+    }
+
+    pub fn speed(&self) -> Option<f64> {
+        self.total.speed()
+    }
+
+    pub fn recent_speed(&self) -> Option<f64> {
+        self.previous.unwrap_or(self.current).speed()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct DecodeSpan {
+    decoded: Duration,
+    busy: Duration,
+}
+
+impl DecodeSpan {
+    fn add(&mut self, decoded: Duration, busy: Duration) {
+        self.decoded += decoded;
+        self.busy += busy;
+    }
+
+    fn speed(&self) -> Option<f64> {
+        (!self.busy.is_zero()).then(|| self.decoded.as_secs_f64() / self.busy.as_secs_f64())
+    }
+}
+
+pub(crate) fn frames_to_duration(frames: u64, sample_rate: u32) -> Duration {
+    Duration::from_secs_f64(frames as f64 / sample_rate.max(1) as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 1_000;
+
+    #[test]
+    fn underruns_are_reported_once() {
+        let counter = UnderrunCounter::new();
+        let mut log = UnderrunLog::default();
+        assert!(log.report(&counter, SAMPLE_RATE).is_none());
+
+        counter.record(10);
+        counter.record(20);
+        let underrun = log.report(&counter, SAMPLE_RATE).unwrap();
+        assert_eq!(underrun.count, 2);
+        assert_eq!(underrun.silence, Duration::from_millis(30));
+        assert!(log.report(&counter, SAMPLE_RATE).is_none());
+    }
+
+    #[test]
+    fn only_recent_underruns_are_kept() {
+        let counter = UnderrunCounter::new();
+        let mut log = UnderrunLog::default();
+        for frames in 1..=RECENT_UNDERRUNS as u64 + 4 {
+            counter.record(frames);
+            log.report(&counter, SAMPLE_RATE);
+        }
+
+        let recent = log.recent();
+        assert_eq!(recent.len(), RECENT_UNDERRUNS);
+
+        // oldest first, with the first 4 dropped
+        assert_eq!(recent[0].silence, frames_to_duration(5, SAMPLE_RATE));
+        assert_eq!(
+            recent[RECENT_UNDERRUNS - 1].silence,
+            frames_to_duration(RECENT_UNDERRUNS as u64 + 4, SAMPLE_RATE)
+        );
+    }
+
+    #[test]
+    fn recent_speed_is_of_the_last_full_window() {
+        let mut meter = DecodeMeter::default();
+        assert_eq!(meter.recent_speed(), None);
+
+        // until a window fills, the audio decoded so far is used
+        meter.record(Duration::from_secs(9), Duration::from_secs(1));
+        assert_eq!(meter.recent_speed(), Some(9.0));
+
+        meter.record(Duration::from_secs(1), Duration::from_secs(1));
+        assert_eq!(meter.recent_speed(), Some(5.0));
+
+        // past the window's edge, a new window is started
+        meter.record(Duration::from_secs(5), Duration::from_secs(5));
+        assert_eq!(meter.recent_speed(), Some(5.0));
+        meter.record(Duration::from_secs(5), Duration::ZERO);
+        assert_eq!(meter.recent_speed(), Some(2.0));
+
+        assert_eq!(meter.speed(), Some(20.0 / 7.0));
+    }
+}
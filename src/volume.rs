@@ -0,0 +1,274 @@
+//! Software volume, applied as audio is played.
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Time taken to ramp from silent to full gain.
+/// Ramping avoids the zipper noise of jumping between gains.
+const RAMP_DURATION: Duration = Duration::from_millis(20);
+
+/// Gain of the audio.
+/// Shared between an [`AudioOutput`](crate::AudioOutput) and its stream's callback.
+#[derive(Debug)]
+pub(crate) struct Gain(AtomicU32);
+
+impl Gain {
+    pub fn new(gain: f32) -> Self {
+        Self(AtomicU32::new(gain.to_bits()))
+    }
+
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, gain: f32) {
+        self.0.store(gain.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Follows the target [`Gain`] a little each frame.
+pub(crate) struct GainRamp {
+    target: Arc<Gain>,
+    current: f32,
+
+    /// Largest change in gain between frames.
+    step: f32,
+}
+
+impl GainRamp {
+    pub fn new(target: Arc<Gain>, sample_rate: u32) -> Self {
+        let ramp_frames = (RAMP_DURATION.as_secs_f32() * sample_rate as f32).max(1.0);
+        Self {
+            current: target.get(),
+            target,
+            step: 1.0 / ramp_frames,
+        }
+    }
+
+    /// Gain of the next frame.
+    pub fn next(&mut self) -> f32 {
+        let diff = self.target.get() - self.current;
+        self.current += diff.clamp(-self.step, self.step);
+        self.current
+    }
+}
+
+/// Fade shared between an [`AudioOutput`](crate::AudioOutput) and its stream's callback.
+#[derive(Debug)]
+pub(crate) struct Fade {
+    /// Fade out and hold at silence.
+    paused: AtomicBool,
+
+    /// The callback has faded out to silence.
+    silent: AtomicBool,
+
+    /// Length of a fade in frames.
+    frames: AtomicU32,
+}
+
+impl Fade {
+    /// A paused, silent fade.
+    pub fn new(duration: Duration, sample_rate: u32) -> Self {
+        let fade = Self {
+            paused: AtomicBool::new(true),
+            silent: AtomicBool::new(true),
+            frames: AtomicU32::new(0),
+        };
+        fade.set_duration(duration, sample_rate);
+        fade
+    }
+
+    pub fn set_duration(&self, duration: Duration, sample_rate: u32) {
+        let frames = (duration.as_secs_f64() * sample_rate as f64).round() as u32;
+        self.frames.store(frames.max(1), Ordering::Relaxed);
+    }
+
+    pub fn duration(&self, sample_rate: u32) -> Duration {
+        Duration::from_secs_f64(self.frames.load(Ordering::Relaxed) as f64 / sample_rate as f64)
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Release);
+    }
+
+    pub fn is_silent(&self) -> bool {
+        self.silent.load(Ordering::Acquire)
+    }
+}
+
+/// Follows a [`Fade`] a frame at a time in the output callback.
+pub(crate) struct FadeRamp {
+    fade: Arc<Fade>,
+    current: f32,
+}
+
+impl FadeRamp {
+    pub fn new(fade: Arc<Fade>) -> Self {
+        Self { fade, current: 0.0 }
+    }
+
+    /// The fade is paused and has reached silence,
+    /// so no audio should be played.
+    pub fn is_held(&self) -> bool {
+        self.current == 0.0 && self.fade.paused.load(Ordering::Acquire)
+    }
+
+    /// Gain of the next frame.
+    ///
+    /// # Arguments
+    /// + `fade_out`: Fade out, even if not paused.
+    pub fn next(&mut self, fade_out: bool) -> f32 {
+        let step = 1.0 / self.fade.frames.load(Ordering::Relaxed) as f32;
+        let target = if fade_out || self.fade.paused.load(Ordering::Acquire) {
+            0.0
+        } else {
+            1.0
+        };
+
+        self.current += (target - self.current).clamp(-step, step);
+        self.fade
+            .silent
+            .store(self.current == 0.0, Ordering::Release);
+
+        self.current
+    }
+}
+
+/// Gain of a volume.
+/// Volumes follow a cubic curve, so equal steps in volume sound equally loud.
+///
+/// # Arguments
+/// + `volume`: From `0` (silent) to `1` (full).
+pub(crate) fn volume_to_gain(volume: f32) -> f32 {
+    volume.clamp(0.0, 1.0).powi(3)
+}
+
+/// Applies a gain to interleaved samples,
+/// ramping to it from the previous gain over the samples.
+///
+/// # Arguments
+/// + `channels`: Number of channels the samples are interleaved over.
+/// + `from`: Gain the previous samples were played at.
+/// + `to`: Gain to play the samples at.
+pub(crate) fn apply_gain(samples: &mut [f32], channels: u16, from: f32, to: f32) {
+    let channels = channels.max(1) as usize;
+    let frames = samples.len() / channels;
+    if from == to || frames == 0 {
+        if to != 1.0 {
+            samples.iter_mut().for_each(|sample| *sample *= to);
+        }
+        return;
+    }
+
+    for (idx, frame) in samples.chunks_mut(channels).enumerate() {
+        let gain = from + (to - from) * (idx + 1) as f32 / frames as f32;
+        frame.iter_mut().for_each(|sample| *sample *= gain);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48_000;
+
+    /// Frames [`RAMP_DURATION`] lasts at [`SAMPLE_RATE`].
+    const RAMP_FRAMES: usize = 960;
+
+    /// Steps a ramp through its frame count, checking no step is larger than one increment.
+    ///
+    /// # Returns
+    /// Gain of the last frame.
+    fn run_ramp(ramp: &mut GainRamp, from: f32) -> f32 {
+        let mut previous = from;
+        for _ in 0..RAMP_FRAMES {
+            let gain = ramp.next();
+            assert!(
+                (gain - previous).abs() <= ramp.step * 1.001,
+                "{previous} -> {gain}"
+            );
+            previous = gain;
+        }
+        previous
+    }
+
+    #[test]
+    fn ramp_reaches_target_after_its_frames() {
+        let gain = Arc::new(Gain::new(0.0));
+        let mut ramp = GainRamp::new(gain.clone(), SAMPLE_RATE);
+        assert_eq!(ramp.next(), 0.0);
+
+        gain.set(1.0);
+        let last = run_ramp(&mut ramp, 0.0);
+        assert!((last - 1.0).abs() < 1e-3, "{last}");
+        assert_eq!(ramp.next(), 1.0);
+
+        gain.set(0.0);
+        let last = run_ramp(&mut ramp, 1.0);
+        assert!(last.abs() < 1e-3, "{last}");
+        assert_eq!(ramp.next(), 0.0);
+    }
+
+    #[test]
+    fn volume_follows_cubic_curve() {
+        assert_eq!(volume_to_gain(0.0), 0.0);
+        assert_eq!(volume_to_gain(0.5), 0.125);
+        assert_eq!(volume_to_gain(1.0), 1.0);
+
+        // out of range volumes are clamped
+        assert_eq!(volume_to_gain(-1.0), 0.0);
+        assert_eq!(volume_to_gain(2.0), 1.0);
+    }
+
+    #[test]
+    fn gain_ramps_over_samples() {
+        let mut samples = [1.0; 8];
+        apply_gain(&mut samples, 2, 0.0, 1.0);
+        assert_eq!(samples, [0.25, 0.25, 0.5, 0.5, 0.75, 0.75, 1.0, 1.0]);
+
+        let mut samples = [1.0; 4];
+        apply_gain(&mut samples, 2, 0.5, 0.5);
+        assert_eq!(samples, [0.5; 4]);
+    }
+
+    #[test]
+    fn pause_fades_to_silence() {
+        // 10 frames
+        let fade = Arc::new(Fade::new(Duration::from_millis(10), 1_000));
+        let mut ramp = FadeRamp::new(fade.clone());
+        assert!(ramp.is_held());
+
+        fade.set_paused(false);
+        let gains = (0..10).map(|_| ramp.next(false)).collect::<Vec<_>>();
+        assert!(gains.windows(2).all(|pair| pair[0] < pair[1]), "{gains:?}");
+        assert!((gains[9] - 1.0).abs() < 1e-6, "{gains:?}");
+        assert!(!fade.is_silent());
+
+        fade.set_paused(true);
+        let gains = (0..10).map(|_| ramp.next(false)).collect::<Vec<_>>();
+        assert!(gains.windows(2).all(|pair| pair[0] > pair[1]), "{gains:?}");
+        assert_eq!(ramp.next(false), 0.0);
+        assert!(fade.is_silent());
+        assert!(ramp.is_held());
+    }
+
+    #[test]
+    fn fade_out_is_silent_without_holding() {
+        let fade = Arc::new(Fade::new(Duration::from_millis(2), 1_000));
+        let mut ramp = FadeRamp::new(fade.clone());
+        fade.set_paused(false);
+        for _ in 0..2 {
+            ramp.next(false);
+        }
+
+        for _ in 0..3 {
+            ramp.next(true);
+        }
+        assert!(fade.is_silent());
+        assert!(!ramp.is_held());
+    }
+}